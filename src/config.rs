@@ -1,57 +1,312 @@
+use crate::{error::Error, path};
 use anyhow::{Context, Result};
+use schemars::{schema::RootSchema, schema_for, JsonSchema};
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use serde_json::Value;
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+/// Links the files in the `from` folder into the `to` folder.
+#[derive(Debug, Serialize, Deserialize, JsonSchema, Clone)]
 pub struct LinkLocation {
+    /// Module folder, relative to `kdot.json`.
     pub from: String,
+    /// Folder the module's files are linked into.
     pub to: String,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
 #[serde(untagged)] // More info here: https://serde.rs/enum-representations.html
 pub enum Location {
     Full(LinkLocation),
+    /// Shorthand for `{ "from": <module name>, "to": <this value> }`.
     Paritial(String),
+    /// Several folders linked as one module, e.g. a `config` and a `share` folder.
+    Multiple(Vec<LinkLocation>),
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// One or more shell commands, run in the module folder.
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum Hook {
+    Command(String),
+    Commands(Vec<String>),
+}
+
+/// Commands run around linking and unlinking a module (once, in its first location).
+/// A failing `pre_*` hook aborts, a failing `post_*` hook rolls back the operation.
+#[derive(Debug, Default, Serialize, Deserialize, JsonSchema)]
+pub struct Hooks {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pre_link: Option<Hook>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub post_link: Option<Hook>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pre_unlink: Option<Hook>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub post_unlink: Option<Hook>,
+}
+
+/// What to do with module entries that cannot be linked: unreadable folders, sockets, FIFOs, devices and broken or looping symlinks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum WalkErrors {
+    /// Stop with an error.
+    Fail,
+    /// Log a warning and skip the entry.
+    Warn,
+}
+
+/// How symlinks inside a module folder are linked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Symlinks {
+    /// Link to what the symlink points to, and link the files of symlinked folders one by one.
+    Follow,
+    /// Link to the symlink itself, folders included, so it keeps pointing where it does in the module.
+    Preserve,
+    /// Fail on any symlink.
+    Reject,
+}
+
+/// A single module (a folder of dot files) in `kdot.json`.
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
 pub struct ModuleConfig {
+    /// Name used to select the module on the command line.
     pub name: String,
+    /// Other modules this module depends on.
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub deps: Option<Vec<String>>,
+    /// Labels shown by `kdot list`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tags: Option<Vec<String>>,
+    /// Where the module is linked from and to, one or a list of locations.
     pub location: Location,
+    /// Commands run around linking and unlinking the module.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hooks: Option<Hooks>,
+    /// Arch packages installed by `kdot install`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub packages: Option<Vec<String>>,
+    /// AUR packages installed by `kdot install` (needs `package_manager.aur`).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub aur: Option<Vec<String>>,
+    /// Links the module through the `escalation` command, for folders owned by root such as `/etc`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub root: Option<bool>,
+    /// Links module files starting with `dot-` as files starting with `.` (`dot-bashrc` becomes `.bashrc`).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dotfiles: Option<bool>,
+    /// Files not to link (gitignore syntax), on top of the defaults and the module's `.kdotignore`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ignore: Option<Vec<String>>,
+    /// What to do with module entries that cannot be linked, `warn` (the default) or `fail`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub walk_errors: Option<WalkErrors>,
+    /// How symlinks inside the module are linked, `follow` (the default), `preserve` or `reject`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub symlinks: Option<Symlinks>,
+    /// Glob (relative to the `to` folder) to octal mode (e.g. `".ssh": "700"`) of created folders and decrypted files.
+    /// When several globs match the longest one wins.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub permissions: Option<BTreeMap<String, String>>,
+    /// Module file (or glob, relative to the `from` folder) to the path it is linked to instead of its place in the `to` folder.
+    /// A path ending with `/` is a folder the file is linked into (e.g. `"*.desktop": "/home/user/.local/share/applications/"`).
+    /// When several globs match the longest one wins.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub files: Option<BTreeMap<String, String>>,
+    /// systemd user units enabled and started after linking, disabled and stopped before unlinking.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub services: Option<Vec<String>>,
 }
 
 impl ModuleConfig {
+    pub fn is_root(&self) -> bool {
+        self.root.unwrap_or(false)
+    }
+
+    pub fn is_dotfiles(&self) -> bool {
+        self.dotfiles.unwrap_or(false)
+    }
+
+    pub fn get_walk_errors(&self) -> WalkErrors {
+        self.walk_errors.unwrap_or(WalkErrors::Warn)
+    }
+
+    pub fn get_symlinks(&self) -> Symlinks {
+        self.symlinks.unwrap_or(Symlinks::Follow)
+    }
+
     // TODO: lifetime on LinkLocation
-    pub fn get_link_location(&self) -> LinkLocation {
+    pub fn get_link_locations(&self) -> Vec<LinkLocation> {
         match &self.location {
-            Location::Full(link_location) => link_location.clone(),
-            // TODO: untested!
-            Location::Paritial(to) => LinkLocation {
+            Location::Full(link_location) => vec![link_location.clone()],
+            Location::Multiple(link_locations) => link_locations.clone(),
+            Location::Paritial(to) => vec![LinkLocation {
                 from: self.name.clone(),
                 to: to.clone(),
-            },
+            }],
         }
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Commands used to query and install packages.
+#[derive(Debug, Default, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct PackageManagerConfig {
+    /// Installs packages with `-S --needed`. Defaults to `sudo pacman`.
+    pub install: Option<String>,
+    /// Installs AUR packages with `-S --needed`, e.g. `paru` or `yay`.
+    pub aur: Option<String>,
+    /// Lists installed packages with `-Qq`. Defaults to `pacman`.
+    pub query: Option<String>,
+}
+
+impl PackageManagerConfig {
+    pub fn get_install(&self) -> &str {
+        self.install.as_deref().unwrap_or("sudo pacman")
+    }
+
+    pub fn get_query(&self) -> &str {
+        self.query.as_deref().unwrap_or("pacman")
+    }
+}
+
+/// How encrypted module files (`*.age`) are decrypted.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct SecretsConfig {
+    /// age identity file used to decrypt and encrypt secrets.
+    pub identity: PathBuf,
+    /// Command used to run age. Defaults to `age`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub age: Option<String>,
+}
+
+impl SecretsConfig {
+    pub fn get_age(&self) -> &str {
+        self.age.as_deref().unwrap_or("age")
+    }
+}
+
+/// The `kdot.json` file at the root of the dot files.
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
 pub struct PackageConfig {
     pub modules: Vec<ModuleConfig>,
+    /// File every change kdot makes is appended to.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub log_file: Option<PathBuf>,
+    /// Commands used by `kdot install`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub package_manager: Option<PackageManagerConfig>,
+    /// Command managing the module services. Defaults to `systemctl`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub systemctl: Option<String>,
+    /// Command the filesystem changes of `root` modules run through. Defaults to `sudo`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub escalation: Option<String>,
+    /// Decrypts `*.age` module files when linking, instead of symlinking them.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub secrets: Option<SecretsConfig>,
+    /// File kdot remembers the folders it created in, to remove them again. Defaults to `.kdot-state.json`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state_file: Option<PathBuf>,
+    /// Folder of `kdot.json`, which relative paths in it are resolved against.
+    #[serde(skip)]
+    pub base_dir: PathBuf,
     // locations: Option<HashMap<String, String>>,
 }
 
-pub fn load_package_config(file: &PathBuf) -> Result<PackageConfig> {
-    let data = std::fs::read_to_string(&file).with_context(|| {
-        format!(
-            "Failed to load file \"{}\".",
-            file.as_os_str().to_str().unwrap()
+impl PackageConfig {
+    pub fn get_systemctl(&self) -> &str {
+        self.systemctl.as_deref().unwrap_or("systemctl")
+    }
+
+    pub fn get_escalation(&self) -> &str {
+        self.escalation.as_deref().unwrap_or("sudo")
+    }
+
+    pub fn get_state_file(&self) -> PathBuf {
+        self.resolve_path(
+            self.state_file
+                .as_deref()
+                .unwrap_or_else(|| Path::new(".kdot-state.json")),
         )
-    })?;
+    }
+
+    /// `path` from the config, relative to the folder of `kdot.json` unless it is absolute.
+    pub fn resolve_path(&self, path: impl AsRef<Path>) -> PathBuf {
+        self.base_dir.join(path)
+    }
+}
+
+/// Loads the config `file`, resolving its relative paths against the folder it is in.
+pub fn load_package_config(file: &PathBuf) -> Result<PackageConfig> {
+    let data = std::fs::read_to_string(file)
+        .with_context(|| Error::Config(format!("Failed to load file \"{}\".", file.display())))?;
 
-    let package_config: PackageConfig = serde_json::from_str::<PackageConfig>(&data)
-        .with_context(|| format!("Invalid package configuration."))?;
+    let mut package_config: PackageConfig = serde_json::from_str::<PackageConfig>(&data)
+        .with_context(|| Error::Config(format!("Invalid package configuration.")))?;
+
+    let file = path::absolute_path(file)?;
+    package_config.base_dir = file.parent().unwrap_or(&file).to_path_buf();
 
     Ok(package_config)
 }
+
+pub fn save_package_config(file: &Path, package_config: &PackageConfig) -> Result<()> {
+    let data = serde_json::to_string_pretty(package_config)?;
+
+    std::fs::write(file, data + "\n")
+        .with_context(|| format!("Failed to write file \"{}\".", file.display()))?;
+
+    Ok(())
+}
+
+/// Appends `packages` to the `packages` of the `module` in the config `file`, leaving the rest of the file as is.
+pub fn add_module_packages(file: &Path, module: &str, packages: &[String]) -> Result<()> {
+    let data = std::fs::read_to_string(file)
+        .with_context(|| Error::Config(format!("Failed to load file \"{}\".", file.display())))?;
+    let mut package_config: Value = serde_json::from_str(&data)
+        .with_context(|| Error::Config(format!("Invalid package configuration.")))?;
+
+    let module_config = package_config["modules"]
+        .as_array_mut()
+        .and_then(|modules| {
+            modules
+                .iter_mut()
+                .find(|module_config| module_config["name"] == module)
+        })
+        .ok_or_else(|| Error::InvalidModule(module.to_owned()))?;
+
+    let module_packages = module_config
+        .as_object_mut()
+        .ok_or_else(|| Error::InvalidModule(module.to_owned()))?
+        .entry("packages")
+        .or_insert_with(|| Value::Array(Vec::new()))
+        .as_array_mut()
+        .ok_or_else(|| {
+            Error::Config(format!(
+                "\"packages\" of \"{}\" module is not a list.",
+                module
+            ))
+        })?;
+
+    for package in packages {
+        let package = Value::String(package.clone());
+        if !module_packages.contains(&package) {
+            module_packages.push(package);
+        }
+    }
+
+    let data = serde_json::to_string_pretty(&package_config)?;
+    std::fs::write(file, data + "\n")
+        .with_context(|| format!("Failed to write file \"{}\".", file.display()))?;
+
+    Ok(())
+}
+
+/// JSON Schema of `kdot.json`, for editor completion and validation.
+pub fn package_config_schema() -> RootSchema {
+    schema_for!(PackageConfig)
+}
@@ -0,0 +1,84 @@
+use std::{fmt, io, path::PathBuf};
+
+/// Why kdot failed, for callers that need more than the message.
+/// Carried inside `anyhow::Error`s, find it with [`Error::find`].
+#[derive(Debug)]
+pub enum Error {
+    /// `kdot.json` is missing or invalid.
+    Config(String),
+    /// No module has this name.
+    InvalidModule(String),
+    /// A file is in the way of a link (or of a file kdot creates).
+    Conflict(PathBuf),
+    /// Not allowed to read or change a path.
+    Permission(PathBuf),
+    /// A path kdot was asked to remove is not one it linked.
+    NotOwned(PathBuf),
+    /// A hook, package manager or other external command failed.
+    Command(String),
+}
+
+/// Exit code of failures that are not an [`Error`].
+pub const EXIT_FAILURE: i32 = 1;
+
+impl Error {
+    /// Exit code of the `kdot` binary when it fails with this error.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Config(_) => 2,
+            Error::InvalidModule(_) => 3,
+            Error::Conflict(_) => 4,
+            Error::Permission(_) => 5,
+            Error::NotOwned(_) => 6,
+            Error::Command(_) => 7,
+        }
+    }
+
+    /// The (outermost) kdot error of `error`.
+    pub fn find(error: &anyhow::Error) -> Option<&Error> {
+        error.downcast_ref::<Error>()
+    }
+
+    /// Turns a permission denied I/O error on `path` into a [`Error::Permission`].
+    pub fn from_io(error: io::Error, path: impl Into<PathBuf>) -> anyhow::Error {
+        if error.kind() == io::ErrorKind::PermissionDenied {
+            anyhow::Error::new(error).context(Error::Permission(path.into()))
+        } else {
+            error.into()
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Config(message) | Error::Command(message) => write!(f, "{}", message),
+            Error::InvalidModule(name) => write!(f, "Invalid module \"{}\".", name),
+            Error::Conflict(path) => write!(f, "\"{}\" already exists.", path.display()),
+            Error::Permission(path) => write!(f, "Permission denied for \"{}\".", path.display()),
+            Error::NotOwned(path) => write!(f, "\"{}\" is not linked by kdot.", path.display()),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Exit code of the `kdot` binary when it fails with `error`.
+/// Permission denied I/O errors count as [`Error::Permission`] even when they were not turned into one.
+pub fn exit_code(error: &anyhow::Error) -> i32 {
+    if let Some(error) = Error::find(error) {
+        return error.exit_code();
+    }
+
+    let permission_denied = error.chain().any(|cause| {
+        cause
+            .downcast_ref::<io::Error>()
+            .is_some_and(|cause| cause.kind() == io::ErrorKind::PermissionDenied)
+    });
+
+    if permission_denied {
+        Error::Permission(PathBuf::new()).exit_code()
+    } else {
+        EXIT_FAILURE
+    }
+}
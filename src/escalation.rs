@@ -0,0 +1,126 @@
+//! The filesystem changes kdot makes, run through the escalation command (e.g. `sudo`) for `root` modules.
+//! kdot itself never runs privileged, only these commands do.
+
+use crate::{error::Error, permissions, process, secrets};
+use anyhow::{Context, Result};
+use std::{fs, os::unix::fs as unixfs, path::Path};
+
+/// Runs `program` with `args` through the `escalation` command line.
+fn run(escalation: &str, program: &str, args: &[&std::ffi::OsStr]) -> Result<()> {
+    let mut command = process::command(escalation)?;
+    command.arg(program).args(args);
+
+    process::run(&mut command)
+}
+
+/// Creates a symlink at `path` pointing to `target`.
+pub fn symlink(escalation: Option<&str>, target: &Path, path: &Path) -> Result<()> {
+    match escalation {
+        Some(escalation) => run(
+            escalation,
+            "ln",
+            &[
+                "-s".as_ref(),
+                "--".as_ref(),
+                target.as_os_str(),
+                path.as_os_str(),
+            ],
+        ),
+        None => unixfs::symlink(target, path).map_err(|error| Error::from_io(error, path)),
+    }
+    .with_context(|| {
+        format!(
+            "Failed to symlink \"{}\" -> \"{}\"",
+            path.display(),
+            target.display()
+        )
+    })
+}
+
+/// Creates the folder `path` (its parent must exist).
+pub fn create_dir(escalation: Option<&str>, path: &Path) -> Result<()> {
+    match escalation {
+        Some(escalation) => run(escalation, "mkdir", &["--".as_ref(), path.as_os_str()]),
+        None => fs::create_dir(path).map_err(|error| Error::from_io(error, path)),
+    }
+    .with_context(|| format!("Failed to create \"{}\"", path.display()))
+}
+
+/// Removes the file (or symlink) `path`.
+pub fn remove_file(escalation: Option<&str>, path: &Path) -> Result<()> {
+    match escalation {
+        Some(escalation) => run(escalation, "rm", &["--".as_ref(), path.as_os_str()]),
+        None => fs::remove_file(path).map_err(|error| Error::from_io(error, path)),
+    }
+    .with_context(|| format!("Failed to remove \"{}\"", path.display()))
+}
+
+/// Removes the empty folder `path`.
+pub fn remove_dir(escalation: Option<&str>, path: &Path) -> Result<()> {
+    match escalation {
+        Some(escalation) => run(escalation, "rmdir", &["--".as_ref(), path.as_os_str()]),
+        None => fs::remove_dir(path).map_err(|error| Error::from_io(error, path)),
+    }
+    .with_context(|| format!("Failed to remove \"{}\"", path.display()))
+}
+
+pub fn set_mode(escalation: Option<&str>, path: &Path, mode: u32) -> Result<()> {
+    match escalation {
+        Some(escalation) => run(
+            escalation,
+            "chmod",
+            &[
+                format!("{:o}", mode).as_ref(),
+                "--".as_ref(),
+                path.as_os_str(),
+            ],
+        )
+        .with_context(|| {
+            format!(
+                "Failed to set mode of \"{}\" to {:o}.",
+                path.display(),
+                mode
+            )
+        }),
+        None => permissions::set_mode(path, mode),
+    }
+}
+
+/// Writes `data` to the file `path` with `mode`.
+pub fn write_file(escalation: Option<&str>, path: &Path, data: &[u8], mode: u32) -> Result<()> {
+    match escalation {
+        Some(escalation) => {
+            // Staged in a file only the user can read, then installed with its final mode
+            let staged = tempfile::Builder::new().prefix("kdot-").tempfile()?;
+            fs::write(staged.path(), data)?;
+
+            run(
+                escalation,
+                "install",
+                &[
+                    "-m".as_ref(),
+                    format!("{:o}", mode).as_ref(),
+                    "--".as_ref(),
+                    staged.path().as_os_str(),
+                    path.as_os_str(),
+                ],
+            )
+            .with_context(|| format!("Failed to write \"{}\".", path.display()))
+        }
+        None => secrets::write_secret(path, data, mode),
+    }
+}
+
+/// Reads the file `path`.
+pub fn read_file(escalation: Option<&str>, path: &Path) -> Result<Vec<u8>> {
+    match escalation {
+        Some(escalation) => {
+            let mut command = process::command(escalation)?;
+            command.arg("cat").arg("--").arg(path);
+
+            process::read_bytes(&mut command)
+        }
+        None => fs::read(path).map_err(Into::into),
+    }
+    .with_context(|| format!("Failed to read \"{}\".", path.display()))
+}
@@ -0,0 +1,176 @@
+use crate::escalation;
+use anyhow::Result;
+use std::{
+    fmt, fs, io,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+};
+
+pub mod memory;
+
+/// Type of a filesystem entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    File,
+    Dir,
+    Symlink,
+    /// Sockets, FIFOs and devices.
+    Other,
+}
+
+impl From<fs::FileType> for FileType {
+    fn from(file_type: fs::FileType) -> Self {
+        if file_type.is_symlink() {
+            FileType::Symlink
+        } else if file_type.is_dir() {
+            FileType::Dir
+        } else if file_type.is_file() {
+            FileType::File
+        } else {
+            FileType::Other
+        }
+    }
+}
+
+/// What the symlink layer reads and changes, so it can run on a real or an in-memory filesystem.
+/// Paths are absolute.
+pub trait Filesystem: fmt::Debug {
+    /// Type of `path` itself (`lstat`).
+    fn file_type(&self, path: &Path) -> io::Result<FileType>;
+    /// Type of what `path` resolves to (`stat`), never a symlink.
+    fn resolved_type(&self, path: &Path) -> io::Result<FileType>;
+    fn read_link(&self, path: &Path) -> io::Result<PathBuf>;
+    /// Absolute path with every symlink resolved, `path` must exist.
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf>;
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>>;
+    /// Paths of the entries of the folder `path`, sorted.
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>>;
+    /// Permission bits of `path` itself.
+    fn mode(&self, path: &Path) -> io::Result<u32>;
+
+    /// Creates the folder `path` (its parent must exist).
+    fn create_dir(&self, path: &Path) -> Result<()>;
+    /// Creates a symlink at `path` pointing to `target`.
+    fn symlink(&self, target: &Path, path: &Path) -> Result<()>;
+    /// Removes the file (or symlink) `path`.
+    fn remove_file(&self, path: &Path) -> Result<()>;
+    /// Removes the empty folder `path`.
+    fn remove_dir(&self, path: &Path) -> Result<()>;
+    fn set_mode(&self, path: &Path, mode: u32) -> Result<()>;
+    /// Writes `data` to the file `path` with `mode`.
+    fn write(&self, path: &Path, data: &[u8], mode: u32) -> Result<()>;
+
+    fn exists(&self, path: &Path) -> bool {
+        self.resolved_type(path).is_ok()
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        matches!(self.resolved_type(path), Ok(FileType::File))
+    }
+}
+
+/// Walks the folder `root` depth first (without following symlinks), skipping the entries `filter` rejects.
+/// Returns every entry but `root` itself, and the folders (`root` included) and entries that could not be read with their error.
+pub fn walk(
+    fs: &dyn Filesystem,
+    root: &Path,
+    filter: &dyn Fn(&Path, FileType) -> bool,
+) -> Vec<(PathBuf, io::Result<FileType>)> {
+    let mut entries = Vec::new();
+    let mut folders = vec![root.to_path_buf()];
+
+    while let Some(folder) = folders.pop() {
+        let children = match fs.read_dir(&folder) {
+            Ok(children) => children,
+            Err(error) => {
+                entries.push((folder, Err(error)));
+                continue;
+            }
+        };
+
+        for child in children {
+            match fs.file_type(&child) {
+                Ok(file_type) if !filter(&child, file_type) => {}
+                Ok(FileType::Dir) => {
+                    folders.push(child.clone());
+                    entries.push((child, Ok(FileType::Dir)));
+                }
+                file_type => entries.push((child, file_type)),
+            }
+        }
+    }
+
+    // Depth first order, as a folder sorts right before its entries
+    entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    entries
+}
+
+/// The real filesystem, changed through the escalation command (e.g. `sudo`) for `root` modules.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RealFilesystem<'a> {
+    pub escalation: Option<&'a str>,
+}
+
+impl Filesystem for RealFilesystem<'_> {
+    fn file_type(&self, path: &Path) -> io::Result<FileType> {
+        Ok(fs::symlink_metadata(path)?.file_type().into())
+    }
+
+    fn resolved_type(&self, path: &Path) -> io::Result<FileType> {
+        Ok(fs::metadata(path)?.file_type().into())
+    }
+
+    fn read_link(&self, path: &Path) -> io::Result<PathBuf> {
+        fs::read_link(path)
+    }
+
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        fs::canonicalize(path)
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        match self.escalation {
+            Some(_) => escalation::read_file(self.escalation, path)
+                .map_err(|error| io::Error::other(format!("{:#}", error))),
+            None => fs::read(path),
+        }
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        let mut entries = fs::read_dir(path)?
+            .map(|entry| Ok(entry?.path()))
+            .collect::<io::Result<Vec<_>>>()?;
+        entries.sort();
+
+        Ok(entries)
+    }
+
+    fn mode(&self, path: &Path) -> io::Result<u32> {
+        Ok(fs::symlink_metadata(path)?.permissions().mode() & 0o7777)
+    }
+
+    fn create_dir(&self, path: &Path) -> Result<()> {
+        escalation::create_dir(self.escalation, path)
+    }
+
+    fn symlink(&self, target: &Path, path: &Path) -> Result<()> {
+        escalation::symlink(self.escalation, target, path)
+    }
+
+    fn remove_file(&self, path: &Path) -> Result<()> {
+        escalation::remove_file(self.escalation, path)
+    }
+
+    fn remove_dir(&self, path: &Path) -> Result<()> {
+        escalation::remove_dir(self.escalation, path)
+    }
+
+    fn set_mode(&self, path: &Path, mode: u32) -> Result<()> {
+        escalation::set_mode(self.escalation, path, mode)
+    }
+
+    fn write(&self, path: &Path, data: &[u8], mode: u32) -> Result<()> {
+        escalation::write_file(self.escalation, path, data, mode)
+    }
+}
@@ -0,0 +1,430 @@
+use super::{FileType, Filesystem};
+use anyhow::{bail, Result};
+use std::{
+    cell::RefCell,
+    collections::BTreeMap,
+    ffi::OsString,
+    io,
+    path::{Component, Path, PathBuf},
+};
+
+#[derive(Debug, Clone)]
+enum Node {
+    File { data: Vec<u8>, mode: u32 },
+    Dir { mode: u32 },
+    Symlink(PathBuf),
+    Other,
+}
+
+/// Symlinks followed before a path is considered a loop (like Linux's `ELOOP`).
+const MAX_SYMLINKS: usize = 40;
+
+/// A filesystem kept in memory, for tests.
+/// Permissions are checked as for the owner: folders need `r` to be read and `w` to be changed, files need `r` to be read.
+#[derive(Debug)]
+pub struct MemoryFilesystem {
+    nodes: RefCell<BTreeMap<PathBuf, Node>>,
+}
+
+impl Default for MemoryFilesystem {
+    fn default() -> Self {
+        let mut nodes = BTreeMap::new();
+        nodes.insert(PathBuf::from("/"), Node::Dir { mode: 0o755 });
+
+        MemoryFilesystem {
+            nodes: RefCell::new(nodes),
+        }
+    }
+}
+
+fn error(kind: io::ErrorKind, message: &str, path: &Path) -> io::Error {
+    io::Error::new(kind, format!("{}: \"{}\"", message, path.display()))
+}
+
+fn not_found(path: &Path) -> io::Error {
+    error(io::ErrorKind::NotFound, "No such file or directory", path)
+}
+
+fn permission_denied(path: &Path) -> io::Error {
+    error(io::ErrorKind::PermissionDenied, "Permission denied", path)
+}
+
+impl MemoryFilesystem {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates the file `path` (and its missing folders).
+    pub fn add_file(&self, path: impl AsRef<Path>, data: &[u8]) -> Result<()> {
+        let path = path.as_ref();
+        self.add_dirs(path.parent().unwrap_or(path))?;
+        self.write(path, data, 0o644)
+    }
+
+    /// Creates the folder `path` and its missing parents.
+    pub fn add_dirs(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        if let Ok(FileType::Dir) = self.resolved_type(path) {
+            return Ok(());
+        }
+        if let Some(parent) = path.parent() {
+            self.add_dirs(parent)?;
+        }
+
+        self.create_dir(path)
+    }
+
+    /// Creates a socket, FIFO or device at `path`.
+    pub fn add_other(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        self.check_writable_parent(path)?;
+        self.nodes
+            .borrow_mut()
+            .insert(path.to_path_buf(), Node::Other);
+
+        Ok(())
+    }
+
+    /// `path` with every symlink resolved (but the last component's unless `follow`).
+    /// The last component does not need to exist.
+    fn resolve(&self, path: &Path, follow: bool) -> io::Result<PathBuf> {
+        let nodes = self.nodes.borrow();
+
+        // Components left to resolve, last first
+        let mut pending: Vec<OsString> = Vec::new();
+        push_components(&mut pending, path);
+
+        let mut resolved = PathBuf::from("/");
+        let mut followed = 0;
+
+        while let Some(name) = pending.pop() {
+            if name == ".." {
+                resolved.pop();
+                continue;
+            }
+
+            let next = resolved.join(&name);
+            match nodes.get(&next) {
+                Some(Node::Symlink(target)) if follow || !pending.is_empty() => {
+                    followed += 1;
+                    if followed > MAX_SYMLINKS {
+                        return Err(error(
+                            io::ErrorKind::Other,
+                            "Too many levels of symbolic links",
+                            path,
+                        ));
+                    }
+                    if target.is_absolute() {
+                        resolved = PathBuf::from("/");
+                    }
+                    push_components(&mut pending, target);
+                }
+                Some(Node::Dir { mode }) if !pending.is_empty() => {
+                    // Searching a folder needs `x`
+                    if mode & 0o100 == 0 {
+                        return Err(permission_denied(path));
+                    }
+                    resolved = next;
+                }
+                Some(_) if !pending.is_empty() => return Err(not_found(path)),
+                None if !pending.is_empty() => return Err(not_found(path)),
+                _ => resolved = next,
+            }
+        }
+
+        Ok(resolved)
+    }
+
+    fn node(&self, path: &Path, follow: bool) -> io::Result<(PathBuf, Node)> {
+        let resolved = self.resolve(path, follow)?;
+        let node = self.nodes.borrow().get(&resolved).cloned();
+
+        match node {
+            Some(node) => Ok((resolved, node)),
+            None => Err(not_found(path)),
+        }
+    }
+
+    /// Fails unless the folder of `path` exists and can be changed.
+    fn check_writable_parent(&self, path: &Path) -> io::Result<PathBuf> {
+        let parent = path.parent().ok_or_else(|| permission_denied(path))?;
+
+        match self.node(parent, true)? {
+            (_, Node::Dir { mode }) if mode & 0o200 != 0 => {}
+            (_, Node::Dir { .. }) => return Err(permission_denied(path)),
+            _ => return Err(not_found(path)),
+        }
+
+        self.resolve(path, false)
+    }
+}
+
+/// Pushes the components of `path` onto `pending`, last first.
+fn push_components(pending: &mut Vec<OsString>, path: &Path) {
+    for component in path.components().rev() {
+        match component {
+            Component::Normal(name) => pending.push(name.to_owned()),
+            Component::ParentDir => pending.push(OsString::from("..")),
+            Component::RootDir | Component::CurDir | Component::Prefix(_) => {}
+        }
+    }
+}
+
+impl Filesystem for MemoryFilesystem {
+    fn file_type(&self, path: &Path) -> io::Result<FileType> {
+        Ok(match self.node(path, false)?.1 {
+            Node::File { .. } => FileType::File,
+            Node::Dir { .. } => FileType::Dir,
+            Node::Symlink(_) => FileType::Symlink,
+            Node::Other => FileType::Other,
+        })
+    }
+
+    fn resolved_type(&self, path: &Path) -> io::Result<FileType> {
+        let resolved = self.node(path, true)?.0;
+        self.file_type(&resolved)
+    }
+
+    fn read_link(&self, path: &Path) -> io::Result<PathBuf> {
+        match self.node(path, false)?.1 {
+            Node::Symlink(target) => Ok(target),
+            _ => Err(error(io::ErrorKind::InvalidInput, "Not a symlink", path)),
+        }
+    }
+
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        Ok(self.node(path, true)?.0)
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        match self.node(path, true)?.1 {
+            Node::File { mode, .. } if mode & 0o400 == 0 => Err(permission_denied(path)),
+            Node::File { data, .. } => Ok(data),
+            _ => Err(error(io::ErrorKind::Other, "Not a file", path)),
+        }
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        let (resolved, node) = self.node(path, true)?;
+
+        match node {
+            Node::Dir { mode } if mode & 0o400 == 0 => Err(permission_denied(path)),
+            Node::Dir { .. } => Ok(self
+                .nodes
+                .borrow()
+                .keys()
+                .filter(|child| child.parent() == Some(&resolved))
+                .filter_map(|child| child.file_name())
+                .map(|name| path.join(name))
+                .collect()),
+            _ => Err(error(io::ErrorKind::Other, "Not a directory", path)),
+        }
+    }
+
+    fn mode(&self, path: &Path) -> io::Result<u32> {
+        Ok(match self.node(path, false)?.1 {
+            Node::File { mode, .. } | Node::Dir { mode } => mode,
+            Node::Symlink(_) => 0o777,
+            Node::Other => 0o644,
+        })
+    }
+
+    fn create_dir(&self, path: &Path) -> Result<()> {
+        let resolved = self.check_writable_parent(path)?;
+        if self.file_type(path).is_ok() {
+            bail!("\"{}\" already exists.", path.display());
+        }
+
+        self.nodes
+            .borrow_mut()
+            .insert(resolved, Node::Dir { mode: 0o755 });
+
+        Ok(())
+    }
+
+    fn symlink(&self, target: &Path, path: &Path) -> Result<()> {
+        let resolved = self.check_writable_parent(path)?;
+        if self.file_type(path).is_ok() {
+            bail!("\"{}\" already exists.", path.display());
+        }
+
+        self.nodes
+            .borrow_mut()
+            .insert(resolved, Node::Symlink(target.to_path_buf()));
+
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &Path) -> Result<()> {
+        let resolved = self.check_writable_parent(path)?;
+        if let Node::Dir { .. } = self.node(path, false)?.1 {
+            bail!("\"{}\" is a directory.", path.display());
+        }
+
+        self.nodes.borrow_mut().remove(&resolved);
+
+        Ok(())
+    }
+
+    fn remove_dir(&self, path: &Path) -> Result<()> {
+        let resolved = self.check_writable_parent(path)?;
+        match self.node(path, false)?.1 {
+            Node::Dir { .. } if self.read_dir(path)?.is_empty() => {}
+            Node::Dir { .. } => bail!("\"{}\" is not empty.", path.display()),
+            _ => bail!("\"{}\" is not a directory.", path.display()),
+        }
+
+        self.nodes.borrow_mut().remove(&resolved);
+
+        Ok(())
+    }
+
+    fn set_mode(&self, path: &Path, mode: u32) -> Result<()> {
+        let resolved = self.node(path, true)?.0;
+
+        match self.nodes.borrow_mut().get_mut(&resolved) {
+            Some(Node::File { mode: current, .. }) | Some(Node::Dir { mode: current }) => {
+                *current = mode
+            }
+            _ => bail!("Cannot set the mode of \"{}\".", path.display()),
+        }
+
+        Ok(())
+    }
+
+    fn write(&self, path: &Path, data: &[u8], mode: u32) -> Result<()> {
+        // Like opening the file, writes through symlinks
+        let resolved = self.resolve(path, true)?;
+        self.check_writable_parent(&resolved)?;
+
+        if let Ok(Node::Dir { .. }) = self.node(&resolved, false).map(|(_, node)| node) {
+            bail!("\"{}\" is a directory.", path.display());
+        }
+
+        self.nodes.borrow_mut().insert(
+            resolved,
+            Node::File {
+                data: data.to_vec(),
+                mode,
+            },
+        );
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filesystem::walk;
+
+    #[test]
+    fn resolves_symlinks() -> Result<()> {
+        let fs = MemoryFilesystem::new();
+        fs.add_file("/dots/bash/bashrc", b"bashrc")?;
+        fs.add_dirs("/home")?;
+        fs.symlink(Path::new("/dots/bash"), Path::new("/home/bash"))?;
+        fs.symlink(Path::new("../bash/bashrc"), Path::new("/dots/bash/link"))?;
+
+        assert_eq!(fs.read(Path::new("/home/bash/bashrc"))?, b"bashrc");
+        assert_eq!(
+            fs.canonicalize(Path::new("/home/bash/link"))?,
+            Path::new("/dots/bash/bashrc")
+        );
+        assert_eq!(fs.file_type(Path::new("/home/bash"))?, FileType::Symlink);
+        assert_eq!(fs.resolved_type(Path::new("/home/bash"))?, FileType::Dir);
+        assert_eq!(
+            fs.read_dir(Path::new("/home/bash"))?,
+            vec![
+                PathBuf::from("/home/bash/bashrc"),
+                PathBuf::from("/home/bash/link")
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn detects_symlink_loops() -> Result<()> {
+        let fs = MemoryFilesystem::new();
+        fs.add_dirs("/loop")?;
+        fs.symlink(Path::new("/loop/b"), Path::new("/loop/a"))?;
+        fs.symlink(Path::new("/loop/a"), Path::new("/loop/b"))?;
+
+        assert_eq!(fs.file_type(Path::new("/loop/a"))?, FileType::Symlink);
+        assert!(fs.canonicalize(Path::new("/loop/a")).is_err());
+        assert!(!fs.exists(Path::new("/loop/a")));
+
+        Ok(())
+    }
+
+    #[test]
+    fn checks_permissions() -> Result<()> {
+        let fs = MemoryFilesystem::new();
+        fs.add_file("/etc/secret", b"secret")?;
+        fs.set_mode(Path::new("/etc/secret"), 0o200)?;
+        fs.set_mode(Path::new("/etc"), 0o555)?;
+
+        let error = fs.read(Path::new("/etc/secret")).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+        assert!(fs
+            .symlink(
+                Path::new("/dots/pacman.conf"),
+                Path::new("/etc/pacman.conf")
+            )
+            .is_err());
+        assert!(fs.remove_file(Path::new("/etc/secret")).is_err());
+
+        fs.set_mode(Path::new("/etc"), 0o755)?;
+        fs.remove_file(Path::new("/etc/secret"))?;
+        assert!(!fs.exists(Path::new("/etc/secret")));
+
+        Ok(())
+    }
+
+    #[test]
+    fn walks_folders_in_order() -> Result<()> {
+        let fs = MemoryFilesystem::new();
+        fs.add_file("/dots/b", b"")?;
+        fs.add_file("/dots/a/2", b"")?;
+        fs.add_file("/dots/a/1", b"")?;
+        fs.add_file("/dots/skip/file", b"")?;
+
+        let entries: Vec<_> = walk(&fs, Path::new("/dots"), &|path, _| !path.ends_with("skip"))
+            .into_iter()
+            .map(|(path, file_type)| (path, file_type.unwrap()))
+            .collect();
+
+        assert_eq!(
+            entries,
+            vec![
+                (PathBuf::from("/dots/a"), FileType::Dir),
+                (PathBuf::from("/dots/a/1"), FileType::File),
+                (PathBuf::from("/dots/a/2"), FileType::File),
+                (PathBuf::from("/dots/b"), FileType::File),
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn walks_past_unreadable_folders() -> Result<()> {
+        let fs = MemoryFilesystem::new();
+        fs.add_file("/dots/a/1", b"")?;
+        fs.add_file("/dots/b", b"")?;
+        fs.set_mode(Path::new("/dots/a"), 0o300)?;
+
+        let entries = walk(&fs, Path::new("/dots"), &|_, _| true);
+
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[1].0, Path::new("/dots/a"));
+        assert_eq!(
+            entries[1].1.as_ref().unwrap_err().kind(),
+            io::ErrorKind::PermissionDenied
+        );
+        assert_eq!(entries[2].0, Path::new("/dots/b"));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,83 @@
+use crate::{
+    config::{Hook, ModuleConfig},
+    output::{self, Event},
+    process,
+};
+use anyhow::{Context, Result};
+use std::path::Path;
+
+/// When a hook runs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HookKind {
+    PreLink,
+    PostLink,
+    PreUnlink,
+    PostUnlink,
+}
+
+impl HookKind {
+    /// Name of the hook in `kdot.json`.
+    pub fn name(self) -> &'static str {
+        match self {
+            HookKind::PreLink => "pre_link",
+            HookKind::PostLink => "post_link",
+            HookKind::PreUnlink => "pre_unlink",
+            HookKind::PostUnlink => "post_unlink",
+        }
+    }
+}
+
+fn get_hook(module: &ModuleConfig, kind: HookKind) -> Option<&Hook> {
+    let hooks = module.hooks.as_ref()?;
+
+    match kind {
+        HookKind::PreLink => hooks.pre_link.as_ref(),
+        HookKind::PostLink => hooks.post_link.as_ref(),
+        HookKind::PreUnlink => hooks.pre_unlink.as_ref(),
+        HookKind::PostUnlink => hooks.post_unlink.as_ref(),
+    }
+}
+
+/// Runs the module's `kind` hook commands with `sh` in the module folder (`from`), stopping at the first failing command.
+/// ## Environment
+/// - `KDOT_MODULE` the module name
+/// - `KDOT_MODULE_DIR` the absolute module folder (of the first location)
+/// - `KDOT_TARGET_DIR` the absolute folder the module is linked into (of the first location)
+/// - `KDOT_HOOK` the hook name (e.g. `post_link`)
+pub fn run_hook(module: &ModuleConfig, kind: HookKind, from: &Path, to: &Path) -> Result<()> {
+    let commands = match get_hook(module, kind) {
+        Some(Hook::Command(command)) => vec![command],
+        Some(Hook::Commands(commands)) => commands.iter().collect(),
+        None => return Ok(()),
+    };
+
+    for command in commands {
+        output::emit(
+            Some(&module.name),
+            Event::Hook {
+                hook: kind.name(),
+                command,
+            },
+        );
+
+        let mut hook = process::command("sh")?;
+        hook.arg("-c")
+            .arg(command)
+            .current_dir(from)
+            .env("KDOT_MODULE", &module.name)
+            .env("KDOT_MODULE_DIR", from)
+            .env("KDOT_TARGET_DIR", to)
+            .env("KDOT_HOOK", kind.name());
+
+        process::run(&mut hook).with_context(|| {
+            format!(
+                "The {} hook \"{}\" of \"{}\" module failed.",
+                kind.name(),
+                command,
+                module.name
+            )
+        })?;
+    }
+
+    Ok(())
+}
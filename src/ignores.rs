@@ -0,0 +1,71 @@
+use crate::{error::Error, filesystem::Filesystem};
+use anyhow::{Context, Result};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use std::path::Path;
+
+/// Ignore file at the root of a module (gitignore syntax).
+pub const IGNORE_FILE: &str = ".kdotignore";
+
+/// Files never linked unless a module re-includes them (e.g. `!README.md`).
+const DEFAULT_IGNORES: &[&str] = &[
+    // Version control
+    ".git",
+    ".gitmodules",
+    ".hg",
+    ".svn",
+    IGNORE_FILE,
+    // Editor and OS leftovers
+    "*~",
+    ".*.sw[po]",
+    "#*#",
+    ".#*",
+    ".DS_Store",
+    // Module documentation
+    "/README*",
+    "/LICENSE*",
+    "/COPYING",
+];
+
+/// Builds the ignore rules of the module folder `to`: the defaults, then `ignore` from kdot.json, then the module's `.kdotignore`.
+/// Later rules take precedence.
+pub fn build_ignore(
+    fs: &dyn Filesystem,
+    to: &Path,
+    ignore: Option<&Vec<String>>,
+) -> Result<Gitignore> {
+    let mut builder = GitignoreBuilder::new(to);
+
+    let lines = DEFAULT_IGNORES
+        .iter()
+        .copied()
+        .chain(ignore.into_iter().flatten().map(String::as_str));
+
+    for line in lines {
+        builder
+            .add_line(None, line)
+            .with_context(|| Error::Config(format!("Invalid ignore pattern \"{}\".", line)))?;
+    }
+
+    let ignore_file = to.join(IGNORE_FILE);
+    if fs.is_file(&ignore_file) {
+        let data = fs.read(&ignore_file)?;
+
+        for line in String::from_utf8_lossy(&data).lines() {
+            builder
+                .add_line(Some(ignore_file.clone()), line)
+                .with_context(|| {
+                    Error::Config(format!(
+                        "Invalid ignore file \"{}\".",
+                        ignore_file.display()
+                    ))
+                })?;
+        }
+    }
+
+    Ok(builder.build()?)
+}
+
+/// Whether `relative` (a path inside the module) is ignored.
+pub fn is_ignored(ignore: &Gitignore, relative: &Path, is_dir: bool) -> bool {
+    ignore.matched(relative, is_dir).is_ignore()
+}
@@ -0,0 +1,336 @@
+use crate::{
+    config::{save_package_config, LinkLocation, Location, ModuleConfig, PackageConfig},
+    error::Error,
+    filesystem::{self, FileType, RealFilesystem},
+    ignores,
+    module::{link_module, unlink_module, Runtime},
+    path::{self, absolute_path},
+};
+use anyhow::{bail, Context, Result};
+use std::{
+    cell::RefCell,
+    fs,
+    path::{Path, PathBuf},
+};
+use walkdir::WalkDir;
+
+/// Proposes a module for every folder in `scan_dir` (one per subdirectory).
+fn scan_modules(scan_dir: &Path, dotfiles: bool) -> Result<Vec<ModuleConfig>> {
+    let mut folders = Vec::new();
+
+    for entry in fs::read_dir(scan_dir)
+        .with_context(|| format!("Failed to read \"{}\".", scan_dir.display()))?
+    {
+        let entry = entry?;
+        // Only real folders, symlinked folders are most likely already managed
+        if entry.file_type()?.is_dir() {
+            folders.push(entry.path());
+        }
+    }
+
+    folders.sort();
+
+    Ok(folders
+        .into_iter()
+        .filter_map(|folder| {
+            // Module names and locations are JSON strings
+            let (name, to) = match (folder.file_name()?.to_str(), folder.to_str()) {
+                (Some(name), Some(to)) => (name.to_owned(), to.to_owned()),
+                _ => {
+                    warn!("Skipping \"{}\", its name is not UTF-8.", folder.display());
+                    return None;
+                }
+            };
+
+            Some(ModuleConfig {
+                location: Location::Full(LinkLocation {
+                    from: name.clone(),
+                    to,
+                }),
+                name,
+                deps: None,
+                tags: None,
+                hooks: None,
+                packages: None,
+                aur: None,
+                ignore: None,
+                walk_errors: None,
+                symlinks: None,
+                root: None,
+                dotfiles: if dotfiles { Some(true) } else { None },
+                permissions: None,
+                files: None,
+                services: None,
+            })
+        })
+        .collect())
+}
+
+/// Copies the folder `from` to `to`, keeping module links (symlinks) as is.
+fn copy_folder(from: &Path, to: &Path) -> Result<()> {
+    for entry in WalkDir::new(from) {
+        let entry = entry?;
+        let file = entry.path();
+        let target = to.join(file.strip_prefix(from)?);
+
+        if entry.file_type().is_dir() {
+            fs::create_dir_all(&target)?;
+        } else if entry.file_type().is_symlink() {
+            std::os::unix::fs::symlink(fs::read_link(file)?, &target)?;
+        } else {
+            fs::copy(file, &target)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Moves the folder `from` to `to`, leaving an empty `from` folder behind.
+fn move_folder(from: &Path, to: &Path) -> Result<()> {
+    if fs::rename(from, to).is_err() {
+        // Most likely on a different device, fall back to copying
+        copy_folder(from, to)?;
+        fs::remove_dir_all(from)?;
+    }
+
+    fs::create_dir(from)?;
+
+    Ok(())
+}
+
+/// The `dot-` file a `.` file in `folder` would be renamed to, if one of them is already taken.
+fn find_dotfiles_conflict(folder: &Path) -> Result<Option<PathBuf>> {
+    for entry in WalkDir::new(folder).min_depth(1) {
+        let entry = entry?;
+        let name = Path::new(entry.file_name());
+        let renamed = path::to_dotfiles_path(name);
+
+        if renamed != name {
+            let target = entry.path().with_file_name(renamed);
+            if fs::symlink_metadata(&target).is_ok() {
+                return Ok(Some(target));
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+/// Renames every `.` file in `folder` to a `dot-` file, failing on `dot-` files that already exist.
+/// Every rename is added to `renamed` (original and new path) as it happens.
+fn rename_dotfiles(folder: &Path, renamed: &mut Vec<(PathBuf, PathBuf)>) -> Result<()> {
+    // Children first, so renaming a folder does not move the entries left to visit
+    for entry in WalkDir::new(folder).min_depth(1).contents_first(true) {
+        let entry = entry?;
+        let name = Path::new(entry.file_name());
+        let dotfiles_name = path::to_dotfiles_path(name);
+
+        if dotfiles_name != name {
+            let target = entry.path().with_file_name(dotfiles_name);
+            // Renaming would replace it
+            if fs::symlink_metadata(&target).is_ok() {
+                bail!(Error::Conflict(target));
+            }
+            fs::rename(entry.path(), &target)?;
+            renamed.push((entry.path().to_path_buf(), target));
+        }
+    }
+
+    Ok(())
+}
+
+/// Entries of `folder` the module would ignore once adopted (as `dot-` files if `dotfiles` is set), which would not be linked back.
+fn find_ignored(folder: &Path, dotfiles: bool) -> Result<Vec<PathBuf>> {
+    let fs = RealFilesystem::default();
+    let ignore = ignores::build_ignore(&fs, folder, None)?;
+
+    let ignored = RefCell::new(Vec::new());
+    let filter = |path: &Path, file_type| match path.strip_prefix(folder) {
+        Ok(relative) => {
+            let relative = if dotfiles {
+                path::to_dotfiles_path(relative)
+            } else {
+                relative.to_path_buf()
+            };
+
+            if ignores::is_ignored(&ignore, &relative, file_type == FileType::Dir) {
+                ignored.borrow_mut().push(path.to_path_buf());
+                return false;
+            }
+            true
+        }
+        Err(_) => true,
+    };
+    filesystem::walk(&fs, folder, &filter);
+
+    let mut ignored = ignored.into_inner();
+    ignored.sort();
+    Ok(ignored)
+}
+
+/// A scanned folder moved into the repository, remembered to move it back if adopting fails.
+#[derive(Debug)]
+struct Adopted {
+    /// The folder in the repository.
+    from: PathBuf,
+    /// The scanned folder.
+    to: PathBuf,
+    /// `.` files renamed to `dot-` files (original and new path).
+    renamed: Vec<(PathBuf, PathBuf)>,
+}
+
+/// Moves the scanned folder of every module into the repository, adding each one to `adopted` once it is moved.
+fn adopt_modules(
+    package_config: &PackageConfig,
+    dotfiles: bool,
+    adopted: &mut Vec<Adopted>,
+) -> Result<()> {
+    for module in &package_config.modules {
+        let location = &module.get_link_locations()[0];
+        let from = package_config.resolve_path(&location.from);
+        let to = Path::new(&location.to);
+
+        move_folder(to, &from).with_context(|| format!("Failed to adopt \"{}\".", location.to))?;
+        adopted.push(Adopted {
+            from: from.clone(),
+            to: to.to_path_buf(),
+            renamed: Vec::new(),
+        });
+
+        if dotfiles {
+            let renamed = &mut adopted.last_mut().unwrap().renamed;
+            rename_dotfiles(&from, renamed)?;
+        }
+        info!("Adopted \"{}\" into \"{}\"", location.to, location.from);
+    }
+
+    Ok(())
+}
+
+/// Moves the `adopted` folders back where they were scanned, with their original names.
+fn restore_modules(adopted: &[Adopted]) -> Result<()> {
+    for adopted in adopted.iter().rev() {
+        for (original, renamed) in adopted.renamed.iter().rev() {
+            fs::rename(renamed, original)?;
+        }
+
+        // Left empty by moving (and unlinking)
+        fs::remove_dir(&adopted.to)?;
+        move_folder(&adopted.from, &adopted.to)?;
+        fs::remove_dir(&adopted.from)?;
+        info!(
+            "Moved \"{}\" back to \"{}\"",
+            adopted.from.display(),
+            adopted.to.display()
+        );
+    }
+
+    Ok(())
+}
+
+/// Creates a `kdot.json` in `dir`, optionally with a module for every folder in `scan`.
+/// When `adopt` is set the contents of every scanned folder are moved into `dir` and linked back.
+/// If adopting fails the folders are moved back and no `kdot.json` is left.
+/// When `dotfiles` is set the modules use `dot-` files, and adopted `.` files are renamed to them.
+pub fn init(dir: &Path, scan: Option<&Path>, adopt: bool, dotfiles: bool) -> Result<()> {
+    fs::create_dir_all(dir).with_context(|| format!("Failed to create \"{}\".", dir.display()))?;
+    let dir = absolute_path(dir)?;
+
+    let config_file = dir.join("kdot.json");
+    if config_file.exists() {
+        bail!(Error::Conflict(config_file));
+    }
+
+    let modules = if let Some(scan) = scan {
+        scan_modules(&absolute_path(scan)?, dotfiles)?
+    } else {
+        Vec::new()
+    };
+
+    if adopt {
+        // Before moving anything, so a conflict leaves every folder where it was
+        for module in &modules {
+            let location = &module.get_link_locations()[0];
+
+            let from = dir.join(&location.from);
+            if fs::symlink_metadata(&from).is_ok() {
+                bail!(Error::Conflict(from));
+            }
+            if dotfiles {
+                if let Some(conflict) = find_dotfiles_conflict(Path::new(&location.to))? {
+                    bail!(Error::Conflict(conflict));
+                }
+            }
+
+            // They would be moved away but never linked back
+            let ignored = find_ignored(Path::new(&location.to), dotfiles)?;
+            if !ignored.is_empty() {
+                let paths: Vec<_> = ignored
+                    .iter()
+                    .map(|path| format!("\"{}\"", path.display()))
+                    .collect();
+                bail!(
+                    "Cannot adopt \"{}\", kdot would not link back {}. Move them out of it first.",
+                    location.to,
+                    paths.join(", ")
+                );
+            }
+        }
+    }
+
+    for module in &modules {
+        // Scanned modules have a single location
+        let location = &module.get_link_locations()[0];
+        println!("{}: {} -> {}", module.name, location.from, location.to);
+    }
+
+    let package_config = PackageConfig {
+        modules,
+        log_file: None,
+        package_manager: None,
+        systemctl: None,
+        escalation: None,
+        state_file: None,
+        secrets: None,
+        base_dir: dir,
+    };
+
+    if !adopt {
+        save_package_config(&config_file, &package_config)?;
+        info!("Created \"{}\"", config_file.display());
+        return Ok(());
+    }
+
+    let mut adopted = Vec::new();
+    let mut linked = Vec::new();
+
+    let result = adopt_modules(&package_config, dotfiles, &mut adopted)
+        .and_then(|_| save_package_config(&config_file, &package_config))
+        .and_then(|_| {
+            info!("Created \"{}\"", config_file.display());
+
+            for module in &package_config.modules {
+                link_module(&package_config, &Runtime::default(), module)?;
+                linked.push(module);
+            }
+            Ok(())
+        });
+
+    if let Err(error) = result {
+        // All or nothing, a scanned folder is never left moved but not linked back
+        warn!("Moving the adopted folders back.");
+        for module in linked.iter().rev() {
+            unlink_module(&package_config, &Runtime::default(), module)?;
+        }
+        restore_modules(&adopted)?;
+
+        for file in [config_file, package_config.get_state_file()] {
+            if file.exists() {
+                fs::remove_file(file)?;
+            }
+        }
+        return Err(error);
+    }
+
+    Ok(())
+}
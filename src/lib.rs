@@ -0,0 +1,88 @@
+//! Links the modules (folders of dot files) described by a `kdot.json`.
+//!
+//! The functions at the root of the crate are the stable API: [`load_config`], [`resolve_modules`],
+//! [`plan`], [`apply`], [`remove`] and [`status`]. The modules below them are what the `kdot` binary is built on
+//! and may change between versions.
+//!
+//! ```no_run
+//! let config = kdot::load_config("dots/kdot.json".as_ref())?;
+//!
+//! for module in kdot::resolve_modules(&config, &["bash".to_owned()])? {
+//!     for change in kdot::plan(&config, module)? {
+//!         println!("{:?}", change);
+//!     }
+//!     kdot::apply(&config, module)?;
+//! }
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+//!
+//! Relative `from`, `to` and `state_file` paths in the config are resolved from the folder of `kdot.json`, whatever
+//! the current directory. [`apply`] and [`remove`] remember the folders kdot created in `state_file`.
+
+#![allow(clippy::useless_format)]
+
+#[macro_use]
+extern crate log;
+
+pub mod config;
+pub mod error;
+mod escalation;
+pub mod filesystem;
+mod hooks;
+mod ignores;
+pub mod init;
+pub mod module;
+pub mod output;
+pub mod packages;
+pub mod path;
+mod permissions;
+mod process;
+pub mod secrets;
+mod services;
+pub mod state;
+pub mod symlink;
+
+use anyhow::Result;
+use std::path::Path;
+
+pub use config::{ModuleConfig, PackageConfig};
+pub use error::Error;
+pub use module::{FileStatus, ModeStatus, ModuleStatus};
+pub use output::FileState;
+pub use symlink::Change;
+
+/// Loads a `kdot.json` file.
+pub fn load_config(file: &Path) -> Result<PackageConfig> {
+    config::load_package_config(&file.to_path_buf())
+}
+
+/// The modules named `names` in config order, or every module if no names are given.
+/// Fails on names that are not in the config.
+pub fn resolve_modules<'a>(
+    config: &'a PackageConfig,
+    names: &[String],
+) -> Result<Vec<&'a ModuleConfig>> {
+    module::get_selected_modules(config, names)
+}
+
+/// The changes [`apply`] would make to the files of the module, without making them.
+pub fn plan(config: &PackageConfig, module: &ModuleConfig) -> Result<Vec<Change>> {
+    module::plan_module(config, &module::Runtime::default(), module)
+}
+
+/// Links the module, running its hooks and enabling its services.
+/// Fails on files in the way, and unlinks it again if a hook or service fails.
+pub fn apply(config: &PackageConfig, module: &ModuleConfig) -> Result<()> {
+    module::link_module(config, &module::Runtime::default(), module)
+}
+
+/// Unlinks the module, leaving files kdot did not create alone.
+/// Fails with [`Error::NotOwned`] once done if it left any of them.
+pub fn remove(config: &PackageConfig, module: &ModuleConfig) -> Result<()> {
+    module::unlink_module(config, &module::Runtime::default(), module)
+}
+
+/// State of every file of the module.
+pub fn status(config: &PackageConfig, module: &ModuleConfig) -> Result<ModuleStatus> {
+    module::get_module_status(config, &module::Runtime::default(), module)
+}
@@ -1,103 +1,352 @@
-#![allow(clippy::useless_format)]
-
 #[macro_use]
 extern crate log;
 extern crate simplelog;
 
-use anyhow::{bail, Result};
+use anyhow::{anyhow, bail, Context, Result};
+use kdot::{
+    config,
+    config::{ModuleConfig, PackageConfig},
+    error, init,
+    module::*,
+    output, packages, path, Error,
+};
 use simplelog::*;
-use std::path::PathBuf;
+use std::{
+    fs::OpenOptions,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
 use structopt::StructOpt;
 
-mod config;
-mod module;
-mod path;
-mod symlink;
-
-use module::*;
+use output::{Event, Format};
 
 #[derive(StructOpt, Debug)]
 struct Cli {
     #[structopt(subcommand)]
     pattern: Command,
 
-    #[structopt(short = "v", long = "verbose")]
-    verbosity: Option<String>,
+    /// Logs more (-v info, -vv debug, -vvv trace). Defaults to the KDOT_LOG environment variable.
+    #[structopt(short, long, global = true, parse(from_occurrences))]
+    verbose: u8,
+
+    /// Only logs errors.
+    #[structopt(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Appends every change kdot makes to this file (overrides "log_file" in kdot.json).
+    #[structopt(long, global = true)]
+    log_file: Option<PathBuf>,
+
+    /// Links every module inside this folder instead of `/` (e.g. to stage a home folder).
+    #[structopt(long, global = true, alias = "target-root")]
+    root: Option<PathBuf>,
+
+    /// Output format.
+    #[structopt(long, global = true, default_value = "human", possible_values = Format::VARIANTS)]
+    format: Format,
 }
 
 #[derive(StructOpt, Debug)]
 enum Command {
+    #[structopt(flatten)]
+    Config(ConfigCommand),
+    /// Prints the JSON Schema of the kdot.json file.
+    Schema,
+    /// Creates a kdot.json file in the directory.
+    Init {
+        /// Directory of the dot files.
+        #[structopt(default_value = ".")]
+        dir: PathBuf,
+        /// Proposes a module for every folder in this directory (e.g. ~/.config).
+        #[structopt(long)]
+        scan: Option<PathBuf>,
+        /// Moves the scanned folders into the directory and links them back.
+        #[structopt(long, requires = "scan")]
+        adopt: bool,
+        /// Uses "dot-" names for "." files in the modules (adopted files are renamed).
+        #[structopt(long)]
+        dotfiles: bool,
+    },
+}
+
+/// Commands that need a kdot.json file.
+#[derive(StructOpt, Debug)]
+enum ConfigCommand {
     /// Links the module to the system.
     Link { modules: Vec<String> },
     /// Unlinks the module to the system.
     Unlink { modules: Vec<String> },
     /// Unlinks and then relinks the module to the system.
     Sync { modules: Vec<String> },
+    /// Shows whether the files of the module are linked (all modules if none are given).
+    Status { modules: Vec<String> },
+    /// Installs the missing packages of the modules (all modules if none are given).
+    Install { modules: Vec<String> },
+    /// Compares the packages of the modules with the installed packages.
+    Packages(PackagesCommand),
+    /// Manages the encrypted files of a module.
+    Secret(SecretCommand),
+    /// Lists the modules and whether they are linked.
+    List,
+}
+
+#[derive(StructOpt, Debug)]
+enum PackagesCommand {
+    /// Lists declared packages that are not installed and explicitly installed packages that are not declared.
+    Diff {
+        /// Modules to compare (all modules if none are given).
+        modules: Vec<String>,
+        /// Appends the undeclared packages to this module in kdot.json.
+        #[structopt(long)]
+        add_to: Option<String>,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+enum SecretCommand {
+    /// Encrypts a file into the module (keeping its path relative to the module's "to").
+    Add { module: String, file: PathBuf },
+    /// Decrypts a secret of the module into $EDITOR and encrypts it back.
+    Edit {
+        module: String,
+        /// Path of the secret in the module, with or without ".age".
+        file: PathBuf,
+    },
+}
+
+fn main() {
+    if let Err(error) = try_main() {
+        eprintln!("Error: {:?}", error);
+        std::process::exit(error::exit_code(&error));
+    }
 }
 
-fn main() -> Result<()> {
+fn try_main() -> Result<()> {
     let args = Cli::from_args();
 
-    let log_level = {
-        if let Some(value) = args.verbosity {
-            match value.as_str() {
-                "verbose" => LevelFilter::Info,
-                "debug" => LevelFilter::Debug,
-                "trace" => LevelFilter::Trace,
-                _ => {
-                    bail!("Invalid \"verbose\" option.");
-                }
-            }
-        } else {
-            LevelFilter::Warn
-        }
+    let log_level = match (args.quiet, args.verbose) {
+        (true, _) => LevelFilter::Error,
+        (false, 0) => match std::env::var("KDOT_LOG") {
+            Ok(value) => LevelFilter::from_str(&value)
+                .map_err(|_| anyhow!("Invalid \"KDOT_LOG\" value \"{}\".", value))?,
+            Err(_) => LevelFilter::Warn,
+        },
+        (false, 1) => LevelFilter::Info,
+        (false, 2) => LevelFilter::Debug,
+        (false, _) => LevelFilter::Trace,
     };
 
-    CombinedLogger::init(vec![
-        TermLogger::new(log_level, Config::default(), TerminalMode::Mixed),
-        // WriteLogger::new(LevelFilter::Info, Config::default(), File::create("my_rust_binary.log").unwrap()),
-    ])
-    .unwrap();
+    // Only the JSON events go to stdout, logs go to stderr
+    let terminal_mode = match args.format {
+        Format::Human => TerminalMode::Mixed,
+        Format::Json => TerminalMode::Stderr,
+    };
 
-    let kdot_config = config::load_package_config(&PathBuf::from("kdot.json"))?;
-    let map = get_module_map(&kdot_config);
+    let mut loggers: Vec<Box<dyn SharedLogger>> =
+        vec![TermLogger::new(log_level, Config::default(), terminal_mode)];
+
+    // Not every command needs one, so only fail once a command uses it
+    let kdot_config = config::load_package_config(&PathBuf::from("kdot.json"));
+
+    let log_file = args.log_file.clone().or_else(|| {
+        kdot_config
+            .as_ref()
+            .ok()
+            .and_then(|kdot_config| kdot_config.log_file.clone())
+    });
+
+    if let Some(log_file) = log_file {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&log_file)
+            .with_context(|| format!("Failed to open log file \"{}\".", log_file.display()))?;
+
+        loggers.push(WriteLogger::new(LevelFilter::Info, Config::default(), file));
+    }
+
+    CombinedLogger::init(loggers).unwrap();
+
+    output::init(args.format);
+
+    // Commands that report what they did as events
+    let has_events = matches!(
+        args.pattern,
+        Command::Config(
+            ConfigCommand::Link { .. }
+                | ConfigCommand::Unlink { .. }
+                | ConfigCommand::Sync { .. }
+                | ConfigCommand::Status { .. }
+        )
+    );
+
+    let result = run(args, kdot_config);
+
+    if has_events {
+        // Errors of a module were already reported with its name
+        if let (Err(error), false) = (&result, output::has_errors()) {
+            output::emit(
+                None,
+                Event::Error {
+                    message: &format!("{:#}", error),
+                },
+            );
+        }
+
+        output::finish(result.is_ok());
+    }
 
+    result
+}
+
+/// Reports the error of an operation on the module as an event of the module.
+fn report_error<T>(module: &ModuleConfig, result: Result<T>) -> Result<T> {
+    if let Err(error) = &result {
+        output::emit(
+            Some(&module.name),
+            Event::Error {
+                message: &format!("{:#}", error),
+            },
+        );
+    }
+
+    result
+}
+
+fn run(args: Cli, kdot_config: Result<PackageConfig>) -> Result<()> {
     match args.pattern {
-        Command::Link {
+        Command::Config(command) => {
+            run_config(command, args.root.as_deref(), args.format, kdot_config?)
+        }
+        Command::Schema => {
+            let schema = config::package_config_schema();
+            println!("{}", serde_json::to_string_pretty(&schema)?);
+            Ok(())
+        }
+        Command::Init {
+            dir,
+            scan,
+            adopt,
+            dotfiles,
+        } => init::init(&dir, scan.as_deref(), adopt, dotfiles),
+    }
+}
+
+fn run_config(
+    command: ConfigCommand,
+    root: Option<&Path>,
+    format: Format,
+    kdot_config: PackageConfig,
+) -> Result<()> {
+    let root = root.map(path::absolute_path).transpose()?;
+    let runtime = Runtime {
+        root: root.as_deref(),
+        fs: None,
+    };
+    let map = get_module_map(&kdot_config);
+
+    match command {
+        ConfigCommand::Link {
             modules: modules_names,
         } => {
-            for name in get_matching_modules(&kdot_config, &modules_names) {
-                if let Some(module) = map.get(name) {
-                    link_module(&module)?;
-                } else {
-                    bail!("Invalid module.");
-                }
+            for module in get_named_modules(&kdot_config, &modules_names)? {
+                report_error(module, link_module(&kdot_config, &runtime, module))?;
             }
         }
-        Command::Unlink {
+        ConfigCommand::Unlink {
             modules: modules_names,
         } => {
-            for name in get_matching_modules(&kdot_config, &modules_names) {
-                if let Some(module) = map.get(name) {
-                    unlink_module(&module)?;
-                } else {
-                    bail!("Invalid module.");
-                }
+            for module in get_named_modules(&kdot_config, &modules_names)? {
+                report_error(module, unlink_module(&kdot_config, &runtime, module))?;
+            }
+        }
+        ConfigCommand::Sync {
+            modules: modules_names,
+        } => {
+            for module in get_named_modules(&kdot_config, &modules_names)? {
+                // Try to unlink
+                report_error(module, unlink_module(&kdot_config, &runtime, module))?;
+
+                // Relink
+                report_error(module, link_module(&kdot_config, &runtime, module))?;
             }
         }
-        Command::Sync {
+        ConfigCommand::Status {
             modules: modules_names,
         } => {
-            for name in get_matching_modules(&kdot_config, &modules_names) {
-                if let Some(module) = map.get(name) {
-                    // Try to unlink
-                    unlink_module(*module)?;
-
-                    // Relink
-                    link_module(*module)?;
-                } else {
-                    bail!("Invalid module.");
+            for module in get_selected_modules(&kdot_config, &modules_names)? {
+                report_error(module, status_module(&kdot_config, &runtime, module))?;
+            }
+        }
+        ConfigCommand::Install {
+            modules: modules_names,
+        } => {
+            let modules = get_selected_modules(&kdot_config, &modules_names)?;
+
+            let manager = kdot_config.package_manager.clone().unwrap_or_default();
+            packages::install_module_packages(&manager, &modules)?;
+        }
+        ConfigCommand::Packages(PackagesCommand::Diff {
+            modules: modules_names,
+            add_to,
+        }) => {
+            let modules = get_selected_modules(&kdot_config, &modules_names)?;
+
+            let manager = kdot_config.package_manager.clone().unwrap_or_default();
+            let diff = packages::diff_module_packages(&manager, &modules)?;
+
+            match format {
+                Format::Human => {
+                    println!("Missing (declared but not installed):");
+                    for package in &diff.missing {
+                        println!("  {}", package);
+                    }
+                    println!("Undeclared (installed but not declared):");
+                    for package in &diff.undeclared {
+                        println!("  {}", package);
+                    }
+                }
+                Format::Json => println!("{}", serde_json::to_string_pretty(&diff)?),
+            }
+
+            if let Some(module) = add_to {
+                let undeclared: Vec<String> = diff.undeclared.into_iter().collect();
+                config::add_module_packages(&PathBuf::from("kdot.json"), &module, &undeclared)?;
+                info!("Added {:?} to \"{}\" module.", undeclared, module);
+            }
+        }
+        ConfigCommand::Secret(SecretCommand::Add { module, file }) => match map.get(&module) {
+            Some(module) => add_module_secret(&kdot_config, &runtime, module, &file)?,
+            None => bail!(Error::InvalidModule(module)),
+        },
+        ConfigCommand::Secret(SecretCommand::Edit { module, file }) => match map.get(&module) {
+            Some(module) => edit_module_secret(&kdot_config, &runtime, module, &file)?,
+            None => bail!(Error::InvalidModule(module)),
+        },
+        ConfigCommand::List => {
+            let summaries = kdot_config
+                .modules
+                .iter()
+                .map(|module| get_module_summary(&kdot_config, &runtime, module))
+                .collect::<Result<Vec<_>>>()?;
+
+            match format {
+                Format::Human => {
+                    for summary in summaries {
+                        println!("{} ({})", summary.name, summary.status);
+                        for location in &summary.locations {
+                            println!("  from: {}", location.from.display());
+                            println!("  to: {}", location.to.display());
+                        }
+                        if !summary.deps.is_empty() {
+                            println!("  deps: {}", summary.deps.join(", "));
+                        }
+                        if !summary.tags.is_empty() {
+                            println!("  tags: {}", summary.tags.join(", "));
+                        }
+                    }
                 }
+                Format::Json => println!("{}", serde_json::to_string_pretty(&summaries)?),
             }
         }
     }
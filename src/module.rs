@@ -1,66 +1,568 @@
 use crate::{
-    config::{ModuleConfig, PackageConfig},
-    path::absolute_path,
-    symlink,
-};
-use anyhow::{Context, Result};
-use std::{collections::HashMap, iter::FromIterator};
-use std::{
-    collections::HashSet,
-    path::{Path, PathBuf},
+    config::{ModuleConfig, PackageConfig, SecretsConfig},
+    error::Error,
+    filesystem::{Filesystem, RealFilesystem},
+    hooks::{self, HookKind},
+    output::{self, Event, FileState},
+    path::{self, absolute_path},
+    secrets, services,
+    state::State,
+    symlink::{self, Change, LinkOptions, LinkStatus},
 };
+use anyhow::{bail, Context, Result};
+use serde::Serialize;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 
 // TODO: check is valid to link (no overrides)
 
-pub fn link_module(module: &ModuleConfig) -> Result<()> {
-    let location = module.get_link_location();
-    let to = absolute_path(&Path::new(&location.to))?;
-    let from = absolute_path(&Path::new(&location.from))?;
+/// Settings of a single run, given on the command line instead of in `kdot.json`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Runtime<'a> {
+    /// Folder every `to` path is linked inside of instead of `/` (`--root`).
+    /// Hooks, services and the escalation command are skipped, as they would change the real system.
+    pub root: Option<&'a Path>,
+    /// Filesystem the modules and the state file are read and changed on, instead of the real one.
+    /// Hooks and services are skipped, as they would change the real system.
+    pub fs: Option<&'a dyn Filesystem>,
+}
 
-    info!(
-        "Linking \"{}\" to \"{}\"",
-        to.as_os_str().to_str().unwrap(),
-        from.as_os_str().to_str().unwrap(),
-    );
+impl Runtime<'_> {
+    /// Whether the run changes the real system, and not just the files inside `--root` or another filesystem.
+    fn is_system(&self) -> bool {
+        self.root.is_none() && self.fs.is_none()
+    }
+}
 
-    symlink::link_folder(&to, &from, true)
-        .with_context(|| format!("Failed to link \"{}\" module.", module.name))?;
+/// Runs the `kind` hook of the module, unless the run would not otherwise change the real system.
+fn run_hook(
+    runtime: &Runtime,
+    module: &ModuleConfig,
+    kind: HookKind,
+    from: &Path,
+    to: &Path,
+) -> Result<()> {
+    match runtime.is_system() {
+        false => Ok(()),
+        true => hooks::run_hook(module, kind, from, to),
+    }
+}
+
+/// Enables (or disables) the services of the module, unless the run would not otherwise change the real system.
+fn set_services(
+    kdot_config: &PackageConfig,
+    runtime: &Runtime,
+    module: &ModuleConfig,
+    enable: bool,
+) -> Result<()> {
+    match (runtime.is_system(), enable) {
+        (false, _) => Ok(()),
+        (true, true) => services::enable_services(kdot_config.get_systemctl(), module),
+        (true, false) => services::disable_services(kdot_config.get_systemctl(), module),
+    }
+}
+
+/// The real filesystem, changed through the escalation command for `root` modules (unless inside `--root`).
+/// Only used when the runtime has no filesystem of its own.
+fn get_real_filesystem<'a>(
+    kdot_config: &'a PackageConfig,
+    runtime: &Runtime,
+    module: &ModuleConfig,
+) -> RealFilesystem<'a> {
+    RealFilesystem {
+        escalation: if module.is_root() && runtime.root.is_none() {
+            Some(kdot_config.get_escalation())
+        } else {
+            None
+        },
+    }
+}
+
+fn get_link_options<'a>(
+    kdot_config: &'a PackageConfig,
+    runtime: &Runtime<'a>,
+    module: &'a ModuleConfig,
+    fs: &'a dyn Filesystem,
+    state: &'a RefCell<State>,
+) -> LinkOptions<'a> {
+    LinkOptions {
+        module: &module.name,
+        secrets: kdot_config.secrets.as_ref(),
+        permissions: module.permissions.as_ref(),
+        ignore: module.ignore.as_ref(),
+        walk_errors: module.get_walk_errors(),
+        symlinks: module.get_symlinks(),
+        files: module.files.as_ref(),
+        dotfiles: module.is_dotfiles(),
+        root: runtime.root,
+        fs,
+        state,
+    }
+}
+
+/// Runs `operation` with the state of `kdot_config`, saving the folders it created or removed even if it fails.
+fn with_state<T>(
+    kdot_config: &PackageConfig,
+    runtime: &Runtime,
+    operation: impl FnOnce(&RefCell<State>) -> Result<T>,
+) -> Result<T> {
+    // The state belongs to the user, never escalated
+    let real = RealFilesystem::default();
+    let fs = runtime.fs.unwrap_or(&real);
+
+    let file = kdot_config.get_state_file();
+    let state = State::load(fs, &file)?;
+    let folders = state.folders.clone();
+
+    let state = RefCell::new(state);
+    let result = operation(&state);
+    let state = state.into_inner();
+
+    if state.folders != folders {
+        let saved = state.save(fs, &file);
+        let value = result?;
+        saved?;
+        Ok(value)
+    } else {
+        result
+    }
+}
+
+/// The module and link folders (`from` and `to`) of every location of the module, as absolute paths
+/// (relative ones are in the folder of `kdot.json`). `to` is inside the `--root` folder if one is given.
+fn get_folders(
+    kdot_config: &PackageConfig,
+    runtime: &Runtime,
+    module: &ModuleConfig,
+) -> Result<Vec<(PathBuf, PathBuf)>> {
+    let folders = module
+        .get_link_locations()
+        .iter()
+        .map(|location| {
+            Ok((
+                absolute_path(kdot_config.resolve_path(&location.from))?,
+                path::with_root(
+                    runtime.root,
+                    &absolute_path(kdot_config.resolve_path(&location.to))?,
+                ),
+            ))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    if folders.is_empty() {
+        bail!(Error::Config(format!(
+            "\"{}\" module has no location.",
+            module.name
+        )));
+    }
+
+    Ok(folders)
+}
+
+pub fn link_module(
+    kdot_config: &PackageConfig,
+    runtime: &Runtime,
+    module: &ModuleConfig,
+) -> Result<()> {
+    with_state(kdot_config, runtime, |state| {
+        link_module_with(kdot_config, runtime, module, state)
+    })
+}
+
+fn link_module_with(
+    kdot_config: &PackageConfig,
+    runtime: &Runtime,
+    module: &ModuleConfig,
+    state: &RefCell<State>,
+) -> Result<()> {
+    let folders = get_folders(kdot_config, runtime, module)?;
+    // Hooks run once, in the first location
+    let (from, to) = &folders[0];
+
+    let real = get_real_filesystem(kdot_config, runtime, module);
+    let fs = runtime.fs.unwrap_or(&real);
+    let options = get_link_options(kdot_config, runtime, module, fs, state);
+
+    if let Some(root) = runtime.root {
+        info!(
+            "Skipping the hooks and services of \"{}\" module inside \"{}\".",
+            module.name,
+            root.display()
+        );
+    }
+
+    run_hook(runtime, module, HookKind::PreLink, from, to)?;
+
+    for (from, to) in &folders {
+        info!("Linking \"{}\" to \"{}\"", to.display(), from.display(),);
+
+        let result = symlink::link_folder(&options, to, from, true)
+            .with_context(|| format!("Failed to link \"{}\" module.", module.name));
+
+        if let Err(error) = result {
+            // The module is linked as a whole or not at all, the failing location included
+            warn!("Rolling back linking \"{}\" module.", module.name);
+            symlink::undo(&options)?;
+            return Err(error);
+        }
+    }
+
+    if let Err(error) = set_services(kdot_config, runtime, module, true) {
+        // Only what this run linked, links that were already there stay
+        warn!("Rolling back linking \"{}\" module.", module.name);
+        symlink::undo(&options)?;
+        return Err(error);
+    }
+
+    if let Err(error) = run_hook(runtime, module, HookKind::PostLink, from, to) {
+        warn!("Rolling back linking \"{}\" module.", module.name);
+        // The services were enabled from the links that are about to go away
+        let disabled = set_services(kdot_config, runtime, module, false);
+        symlink::undo(&options)?;
+        disabled?;
+        return Err(error);
+    }
 
     info!("Linked \"{}\" module.", module.name);
 
     Ok(())
 }
 
-pub fn unlink_module(module: &ModuleConfig) -> Result<()> {
+pub fn unlink_module(
+    kdot_config: &PackageConfig,
+    runtime: &Runtime,
+    module: &ModuleConfig,
+) -> Result<()> {
+    with_state(kdot_config, runtime, |state| {
+        unlink_module_with(kdot_config, runtime, module, state)
+    })
+}
+
+fn unlink_module_with(
+    kdot_config: &PackageConfig,
+    runtime: &Runtime,
+    module: &ModuleConfig,
+    state: &RefCell<State>,
+) -> Result<()> {
     info!("Unlinking {} module.", &module.name);
-    let location = module.get_link_location();
-    symlink::unlink_folder(
-        &PathBuf::from(&location.to),
-        &PathBuf::from(&location.from),
-        true,
-    )?;
+    let folders = get_folders(kdot_config, runtime, module)?;
+    let (from, to) = &folders[0];
+
+    let real = get_real_filesystem(kdot_config, runtime, module);
+    let fs = runtime.fs.unwrap_or(&real);
+    let options = get_link_options(kdot_config, runtime, module, fs, state);
+
+    run_hook(runtime, module, HookKind::PreUnlink, from, to)?;
+    set_services(kdot_config, runtime, module, false)?;
+
+    // A file kdot does not own fails the unlink, but only once the rest of the module is unlinked
+    let mut not_owned = Ok(());
+    for (from, to) in &folders {
+        match symlink::unlink_folder(&options, to, from, true) {
+            Err(error) if matches!(Error::find(&error), Some(Error::NotOwned(_))) => {
+                if not_owned.is_ok() {
+                    not_owned = Err(error);
+                }
+            }
+            result => result?,
+        }
+    }
+
+    if let Err(error) = run_hook(runtime, module, HookKind::PostUnlink, from, to) {
+        // Only what this run unlinked, files that were unlinked before stay so
+        warn!("Rolling back unlinking \"{}\" module.", module.name);
+        symlink::undo(&options)?;
+        set_services(kdot_config, runtime, module, true)?;
+        return Err(error);
+    }
+
+    not_owned
+}
+
+/// State of a module file.
+#[derive(Debug, Clone, Serialize)]
+pub struct FileStatus {
+    /// Where the file is linked.
+    #[serde(serialize_with = "path::serialize_lossy")]
+    pub path: PathBuf,
+    /// The module file.
+    #[serde(serialize_with = "path::serialize_lossy")]
+    pub target: PathBuf,
+    pub state: FileState,
+}
+
+/// A folder or decrypted secret whose mode does not match its permission rule.
+#[derive(Debug, Clone, Serialize)]
+pub struct ModeStatus {
+    #[serde(serialize_with = "path::serialize_lossy")]
+    pub path: PathBuf,
+    pub mode: u32,
+    pub expected: u32,
+}
+
+/// State of every file of a module.
+#[derive(Debug, Clone, Serialize)]
+pub struct ModuleStatus {
+    pub files: Vec<FileStatus>,
+    pub modes: Vec<ModeStatus>,
+}
+
+pub fn get_module_status(
+    kdot_config: &PackageConfig,
+    runtime: &Runtime,
+    module: &ModuleConfig,
+) -> Result<ModuleStatus> {
+    let real = get_real_filesystem(kdot_config, runtime, module);
+    let fs = runtime.fs.unwrap_or(&real);
+    // Only linking and unlinking change the state, others start from an empty one
+    let state = RefCell::default();
+    let options = get_link_options(kdot_config, runtime, module, fs, &state);
+
+    let mut status = ModuleStatus {
+        files: Vec::new(),
+        modes: Vec::new(),
+    };
+
+    for (from, to) in get_folders(kdot_config, runtime, module)? {
+        for (path, target, state) in symlink::file_states(&options, &to, &from)? {
+            status.files.push(FileStatus {
+                path,
+                target,
+                state,
+            });
+        }
+
+        for (path, expected, mode) in symlink::get_wrong_modes(&options, &to, &from)? {
+            status.modes.push(ModeStatus {
+                path,
+                mode,
+                expected,
+            });
+        }
+    }
+
+    Ok(status)
+}
+
+/// Reports the state of every file in the module.
+pub fn status_module(
+    kdot_config: &PackageConfig,
+    runtime: &Runtime,
+    module: &ModuleConfig,
+) -> Result<()> {
+    let status = get_module_status(kdot_config, runtime, module)?;
+
+    for file in &status.files {
+        output::emit(
+            Some(&module.name),
+            Event::Status {
+                path: &file.path,
+                target: &file.target,
+                state: file.state,
+            },
+        );
+    }
+
+    for file in &status.modes {
+        output::emit(
+            Some(&module.name),
+            Event::Mode {
+                path: &file.path,
+                mode: &format!("{:o}", file.mode),
+                expected: &format!("{:o}", file.expected),
+            },
+        );
+    }
 
     Ok(())
 }
 
-// TODO: is the best way of handling this?
-// should it be HashSet<String>?
-pub fn get_matching_modules<'a>(
-    kdot_config: &'a PackageConfig,
-    modules_names: &'a [String],
-) -> HashSet<&'a String> {
-    let config_module_names: HashSet<&'a String> = kdot_config
-        .modules
-        .iter()
-        .map(|module| &module.name)
+/// The changes linking the module would make, without making them (hooks and services are not included).
+pub fn plan_module(
+    kdot_config: &PackageConfig,
+    runtime: &Runtime,
+    module: &ModuleConfig,
+) -> Result<Vec<Change>> {
+    let real = get_real_filesystem(kdot_config, runtime, module);
+    let fs = runtime.fs.unwrap_or(&real);
+    let state = RefCell::default();
+    let options = get_link_options(kdot_config, runtime, module, fs, &state);
+
+    let mut changes = Vec::new();
+    for (from, to) in get_folders(kdot_config, runtime, module)? {
+        changes.extend(symlink::plan_folder(&options, &to, &from)?);
+    }
+
+    Ok(changes)
+}
+
+/// A resolved module location.
+#[derive(Debug, Serialize)]
+pub struct LocationSummary {
+    #[serde(serialize_with = "path::serialize_lossy")]
+    pub from: PathBuf,
+    #[serde(serialize_with = "path::serialize_lossy")]
+    pub to: PathBuf,
+}
+
+/// A module with its resolved locations and link status.
+#[derive(Debug, Serialize)]
+pub struct ModuleSummary {
+    pub name: String,
+    /// The first location.
+    #[serde(serialize_with = "path::serialize_lossy")]
+    pub from: PathBuf,
+    #[serde(serialize_with = "path::serialize_lossy")]
+    pub to: PathBuf,
+    pub locations: Vec<LocationSummary>,
+    pub deps: Vec<String>,
+    pub tags: Vec<String>,
+    pub status: LinkStatus,
+}
+
+pub fn get_module_summary(
+    kdot_config: &PackageConfig,
+    runtime: &Runtime,
+    module: &ModuleConfig,
+) -> Result<ModuleSummary> {
+    let folders = get_folders(kdot_config, runtime, module)?;
+    let real = get_real_filesystem(kdot_config, runtime, module);
+    let fs = runtime.fs.unwrap_or(&real);
+    let state = RefCell::default();
+    let options = get_link_options(kdot_config, runtime, module, fs, &state);
+
+    let mut states = Vec::new();
+    for (from, to) in &folders {
+        states.extend(symlink::file_states(&options, to, from)?);
+    }
+
+    let locations: Vec<_> = folders
+        .into_iter()
+        .map(|(from, to)| LocationSummary { from, to })
         .collect();
 
-    let modules_names: HashSet<&'a String> = HashSet::from_iter(modules_names);
+    Ok(ModuleSummary {
+        name: module.name.clone(),
+        from: locations[0].from.clone(),
+        to: locations[0].to.clone(),
+        locations,
+        deps: module.deps.clone().unwrap_or_default(),
+        tags: module.tags.clone().unwrap_or_default(),
+        status: symlink::get_link_status(&states),
+    })
+}
+
+/// Encrypts `file` into the module as a secret.
+pub fn add_module_secret(
+    kdot_config: &PackageConfig,
+    runtime: &Runtime,
+    module: &ModuleConfig,
+    file: &Path,
+) -> Result<()> {
+    let secrets = get_secrets_config(kdot_config)?;
+    let absolute_file = absolute_path(file)?;
 
-    modules_names
-        .intersection(&config_module_names)
-        .cloned()
-        .collect()
+    // The location the file would be linked into
+    let folders = get_folders(kdot_config, runtime, module)?;
+    let (from, to) = folders
+        .iter()
+        .filter(|(_, to)| absolute_file.starts_with(to))
+        .max_by_key(|(_, to)| to.components().count())
+        .unwrap_or(&folders[0]);
+
+    let secret = secrets::add_secret(secrets, from, to, file, module.is_dotfiles())?;
+    info!(
+        "Encrypted \"{}\" into \"{}\"",
+        file.display(),
+        secret.display()
+    );
+
+    Ok(())
+}
+
+/// Edits the module secret `file` (relative to the module folder, with or without `.age`).
+pub fn edit_module_secret(
+    kdot_config: &PackageConfig,
+    runtime: &Runtime,
+    module: &ModuleConfig,
+    file: &Path,
+) -> Result<()> {
+    let secrets = get_secrets_config(kdot_config)?;
+
+    // Also accept the destination name (`.netrc`) of `dot-` files
+    let file = if module.is_dotfiles() {
+        path::to_dotfiles_path(file)
+    } else {
+        file.to_path_buf()
+    };
+
+    let file = if secrets::is_secret(&file) {
+        file
+    } else {
+        secrets::get_encrypted_path(&file)
+    };
+
+    // The first location that has the secret
+    let folders = get_folders(kdot_config, runtime, module)?;
+    let (from, to) = folders
+        .iter()
+        .find(|(from, _)| from.join(&file).is_file())
+        .unwrap_or(&folders[0]);
+    let secret = from.join(&file);
+
+    let (old, new) = secrets::edit_secret(secrets, &secret)?;
+
+    // A decrypted copy kdot wrote would otherwise no longer match the secret when unlinking
+    with_state(kdot_config, runtime, |state| {
+        let real = get_real_filesystem(kdot_config, runtime, module);
+        let fs = runtime.fs.unwrap_or(&real);
+        let options = get_link_options(kdot_config, runtime, module, fs, state);
+        symlink::update_decrypted(&options, to, from, &secret, &old, &new)
+    })
+}
+
+fn get_secrets_config(kdot_config: &PackageConfig) -> Result<&SecretsConfig> {
+    kdot_config
+        .secrets
+        .as_ref()
+        .ok_or_else(|| Error::Config(format!("No \"secrets\" are configured in kdot.json.")).into())
+}
+
+/// The modules named in `modules_names`, in config order, or every module if no names are given.
+/// Fails on names that are not in the config.
+pub fn get_selected_modules<'a>(
+    kdot_config: &'a PackageConfig,
+    modules_names: &[String],
+) -> Result<Vec<&'a ModuleConfig>> {
+    if modules_names.is_empty() {
+        Ok(kdot_config.modules.iter().collect())
+    } else {
+        get_named_modules(kdot_config, modules_names)
+    }
+}
+
+/// The modules named in `modules_names`, in config order.
+/// Fails on names that are not in the config.
+pub fn get_named_modules<'a>(
+    kdot_config: &'a PackageConfig,
+    modules_names: &[String],
+) -> Result<Vec<&'a ModuleConfig>> {
+    for name in modules_names {
+        if !kdot_config
+            .modules
+            .iter()
+            .any(|module| &module.name == name)
+        {
+            bail!(Error::InvalidModule(name.clone()));
+        }
+    }
+
+    Ok(kdot_config
+        .modules
+        .iter()
+        .filter(|module| modules_names.contains(&module.name))
+        .collect())
 }
 
 pub fn get_module_map<'a>(kdot_config: &'a PackageConfig) -> HashMap<String, &'a ModuleConfig> {
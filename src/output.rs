@@ -0,0 +1,254 @@
+use crate::path;
+use anyhow::{bail, Error};
+use serde::Serialize;
+use std::{
+    path::Path,
+    str::FromStr,
+    sync::{Mutex, OnceLock},
+};
+
+/// How results are printed to stdout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// Readable text.
+    Human,
+    /// JSON for scripts.
+    Json,
+}
+
+impl Format {
+    pub const VARIANTS: &'static [&'static str] = &["human", "json"];
+}
+
+impl FromStr for Format {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "human" => Ok(Format::Human),
+            "json" => Ok(Format::Json),
+            _ => bail!("Invalid \"format\" option."),
+        }
+    }
+}
+
+/// State of a single module file, reported by `kdot status`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FileState {
+    Linked,
+    Unlinked,
+    Conflict,
+}
+
+/// Something kdot did (or found) while working on a module.
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "lowercase")]
+pub enum Event<'a> {
+    /// Created a directory.
+    Mkdir {
+        #[serde(serialize_with = "path::serialize_lossy")]
+        path: &'a Path,
+    },
+    /// Created a symlink at `path` pointing to `target`.
+    Link {
+        #[serde(serialize_with = "path::serialize_lossy")]
+        path: &'a Path,
+        #[serde(serialize_with = "path::serialize_lossy")]
+        target: &'a Path,
+    },
+    /// Left `path` untouched.
+    Skip {
+        #[serde(serialize_with = "path::serialize_lossy")]
+        path: &'a Path,
+        reason: &'a str,
+    },
+    /// Decrypted the secret `target` into `path`.
+    Decrypt {
+        #[serde(serialize_with = "path::serialize_lossy")]
+        path: &'a Path,
+        #[serde(serialize_with = "path::serialize_lossy")]
+        target: &'a Path,
+    },
+    /// `path` is in the way of a link to `target`.
+    Conflict {
+        #[serde(serialize_with = "path::serialize_lossy")]
+        path: &'a Path,
+        #[serde(serialize_with = "path::serialize_lossy")]
+        target: &'a Path,
+    },
+    /// Removed `path`.
+    Remove {
+        #[serde(serialize_with = "path::serialize_lossy")]
+        path: &'a Path,
+    },
+    /// Ran a hook command.
+    Hook { hook: &'a str, command: &'a str },
+    /// Enabled or disabled a systemd user unit.
+    Service { action: &'a str, unit: &'a str },
+    /// The operation failed.
+    Error { message: &'a str },
+    /// `path` has the (octal) `mode` instead of the `expected` one.
+    Mode {
+        #[serde(serialize_with = "path::serialize_lossy")]
+        path: &'a Path,
+        mode: &'a str,
+        expected: &'a str,
+    },
+    /// State of the file at `path`.
+    Status {
+        #[serde(serialize_with = "path::serialize_lossy")]
+        path: &'a Path,
+        #[serde(serialize_with = "path::serialize_lossy")]
+        target: &'a Path,
+        state: FileState,
+    },
+}
+
+#[derive(Serialize)]
+struct Record<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    module: Option<&'a str>,
+    #[serde(flatten)]
+    event: &'a Event<'a>,
+}
+
+/// Number of events of each kind, printed once the command is done.
+#[derive(Debug, Default, Serialize)]
+pub struct Summary {
+    pub mkdir: usize,
+    pub link: usize,
+    pub decrypt: usize,
+    pub skip: usize,
+    pub conflict: usize,
+    pub remove: usize,
+    pub hook: usize,
+    pub service: usize,
+    pub error: usize,
+    pub status: usize,
+    pub mode: usize,
+}
+
+static FORMAT: OnceLock<Format> = OnceLock::new();
+static SUMMARY: Mutex<Summary> = Mutex::new(Summary {
+    mkdir: 0,
+    link: 0,
+    decrypt: 0,
+    skip: 0,
+    conflict: 0,
+    remove: 0,
+    hook: 0,
+    service: 0,
+    error: 0,
+    status: 0,
+    mode: 0,
+});
+
+/// Sets the output format, must be called before any event is emitted.
+pub fn init(format: Format) {
+    FORMAT.set(format).ok();
+}
+
+pub fn format() -> Format {
+    *FORMAT.get().unwrap_or(&Format::Human)
+}
+
+/// Reports an event as a log line, and as a JSON line in JSON mode.
+pub fn emit(module: Option<&str>, event: Event) {
+    {
+        let mut summary = SUMMARY.lock().unwrap();
+        match event {
+            Event::Mkdir { .. } => summary.mkdir += 1,
+            Event::Link { .. } => summary.link += 1,
+            Event::Decrypt { .. } => summary.decrypt += 1,
+            Event::Skip { .. } => summary.skip += 1,
+            Event::Conflict { .. } => summary.conflict += 1,
+            Event::Remove { .. } => summary.remove += 1,
+            Event::Hook { .. } => summary.hook += 1,
+            Event::Service { .. } => summary.service += 1,
+            Event::Error { .. } => summary.error += 1,
+            Event::Status { .. } => summary.status += 1,
+            Event::Mode { .. } => summary.mode += 1,
+        }
+    }
+
+    if format() == Format::Json {
+        let record = Record {
+            module,
+            event: &event,
+        };
+        println!("{}", serde_json::to_string(&record).unwrap());
+    }
+
+    // Also logged in JSON mode (to stderr), so the log file has every change
+    match event {
+        Event::Mkdir { path } => info!("Created \"{}\"", path.display()),
+        Event::Link { path, target } => {
+            info!("Linking \"{}\" -> \"{}\"", path.display(), target.display())
+        }
+        Event::Decrypt { path, target } => info!(
+            "Decrypting \"{}\" -> \"{}\"",
+            path.display(),
+            target.display()
+        ),
+        Event::Skip { path, reason } => info!("Skipping \"{}\" ({})", path.display(), reason),
+        Event::Conflict { path, target } => warn!(
+            "\"{}\" is in the way of linking \"{}\"",
+            path.display(),
+            target.display()
+        ),
+        Event::Remove { path } => info!("Unlinking \"{}\"", path.display()),
+        Event::Hook { hook, command } => info!("Running {} hook \"{}\"", hook, command),
+        Event::Service { action, unit } => info!("Service \"{}\": {}", unit, action),
+        // Printed by `main` when it exits
+        Event::Error { .. } => {}
+        Event::Status { .. } | Event::Mode { .. } if format() == Format::Json => {}
+        Event::Mode {
+            path,
+            mode,
+            expected,
+        } => println!(
+            "{:>8} {} ({} instead of {})",
+            "mode",
+            path.display(),
+            mode,
+            expected
+        ),
+        Event::Status { path, state, .. } => {
+            let state = match state {
+                FileState::Linked => "linked",
+                FileState::Unlinked => "unlinked",
+                FileState::Conflict => "conflict",
+            };
+            println!("{:>8} {} ({})", state, path.display(), module.unwrap_or(""));
+        }
+    }
+}
+
+/// Whether an error event was emitted.
+pub fn has_errors() -> bool {
+    SUMMARY.lock().unwrap().error > 0
+}
+
+/// Prints the summary of every event emitted so far (JSON mode only).
+pub fn finish(success: bool) {
+    if format() != Format::Json {
+        return;
+    }
+
+    #[derive(Serialize)]
+    struct Done<'a> {
+        event: &'static str,
+        success: bool,
+        #[serde(flatten)]
+        summary: &'a Summary,
+    }
+
+    let summary = SUMMARY.lock().unwrap();
+    let done = Done {
+        event: "summary",
+        success,
+        summary: &summary,
+    };
+    println!("{}", serde_json::to_string(&done).unwrap());
+}
@@ -0,0 +1,119 @@
+use crate::{
+    config::{ModuleConfig, PackageManagerConfig},
+    error::Error,
+    process,
+};
+use anyhow::{bail, Result};
+use serde::Serialize;
+use std::collections::{BTreeSet, HashSet};
+
+/// Names of the installed packages, `explicit` limits them to explicitly installed ones.
+pub fn get_installed_packages(
+    manager: &PackageManagerConfig,
+    explicit: bool,
+) -> Result<HashSet<String>> {
+    let mut query = process::command(manager.get_query())?;
+    query.arg(if explicit { "-Qqe" } else { "-Qq" });
+
+    let installed = process::read(&mut query)?;
+
+    Ok(installed
+        .lines()
+        .map(|line| line.trim().to_owned())
+        .collect())
+}
+
+/// Packages of `packages` that are not in `installed`, keeping their order.
+fn get_missing<'a>(packages: &'a [String], installed: &HashSet<String>) -> Vec<&'a String> {
+    let mut seen = HashSet::new();
+
+    packages
+        .iter()
+        .filter(|package| !installed.contains(*package) && seen.insert(*package))
+        .collect()
+}
+
+/// Installs the packages (and AUR packages) of the modules that are not installed yet.
+pub fn install_module_packages(
+    manager: &PackageManagerConfig,
+    modules: &[&ModuleConfig],
+) -> Result<()> {
+    let packages: Vec<String> = modules
+        .iter()
+        .flat_map(|module| module.packages.iter().flatten().cloned())
+        .collect();
+    let aur_packages: Vec<String> = modules
+        .iter()
+        .flat_map(|module| module.aur.iter().flatten().cloned())
+        .collect();
+
+    let installed = get_installed_packages(manager, false)?;
+    let missing = get_missing(&packages, &installed);
+    let missing_aur = get_missing(&aur_packages, &installed);
+
+    if missing.is_empty() && missing_aur.is_empty() {
+        info!("All packages are installed.");
+        return Ok(());
+    }
+
+    if !missing.is_empty() {
+        info!("Installing {:?}", missing);
+
+        let mut install = process::command(manager.get_install())?;
+        install.arg("-S").arg("--needed").args(missing);
+        process::run(&mut install)?;
+    }
+
+    if !missing_aur.is_empty() {
+        let aur = match &manager.aur {
+            Some(aur) => aur,
+            None => bail!(Error::Config(format!(
+                "Installing AUR packages {:?} needs an AUR helper (\"package_manager\".\"aur\" in kdot.json).",
+                missing_aur
+            ))),
+        };
+
+        info!("Installing AUR {:?}", missing_aur);
+
+        let mut install = process::command(aur)?;
+        install.arg("-S").arg("--needed").args(missing_aur);
+        process::run(&mut install)?;
+    }
+
+    Ok(())
+}
+
+/// Difference between the declared and the installed packages.
+#[derive(Debug, Serialize)]
+pub struct PackageDiff {
+    /// Declared but not installed (not even as a dependency).
+    pub missing: BTreeSet<String>,
+    /// Explicitly installed but not declared.
+    pub undeclared: BTreeSet<String>,
+}
+
+/// Compares the packages (and AUR packages) of the modules with the installed packages.
+pub fn diff_module_packages(
+    manager: &PackageManagerConfig,
+    modules: &[&ModuleConfig],
+) -> Result<PackageDiff> {
+    let declared: BTreeSet<String> = modules
+        .iter()
+        .flat_map(|module| {
+            let packages = module.packages.iter().flatten();
+            let aur = module.aur.iter().flatten();
+            packages.chain(aur).cloned()
+        })
+        .collect();
+
+    // A declared package installed as a dependency is not missing
+    let installed: BTreeSet<String> = get_installed_packages(manager, false)?
+        .into_iter()
+        .collect();
+    let explicit: BTreeSet<String> = get_installed_packages(manager, true)?.into_iter().collect();
+
+    Ok(PackageDiff {
+        missing: declared.difference(&installed).cloned().collect(),
+        undeclared: explicit.difference(&declared).cloned().collect(),
+    })
+}
@@ -1,7 +1,13 @@
-use path_clean::PathClean;
+use crate::error::Error;
+use anyhow::{Context, Result};
+use globset::GlobBuilder;
+use serde::Serializer;
+use std::collections::BTreeMap;
 use std::env;
+use std::ffi::OsString;
 use std::io;
-use std::path::{Path, PathBuf};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Component, Path, PathBuf};
 
 pub fn absolute_path(path: impl AsRef<Path>) -> io::Result<PathBuf> {
     let path = path.as_ref();
@@ -10,8 +16,109 @@ pub fn absolute_path(path: impl AsRef<Path>) -> io::Result<PathBuf> {
         path.to_path_buf()
     } else {
         env::current_dir()?.join(path)
+    };
+
+    Ok(clean(&absolute_path))
+}
+
+/// Removes the `.` and `..` components of `path` without touching the filesystem.
+/// Works on components, so names that are not UTF-8 are kept as they are.
+fn clean(path: &Path) -> PathBuf {
+    let mut cleaned = PathBuf::new();
+
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => match cleaned.components().next_back() {
+                Some(Component::Normal(_)) => {
+                    cleaned.pop();
+                }
+                // `/..` is `/`
+                Some(Component::RootDir) | Some(Component::Prefix(_)) => {}
+                _ => cleaned.push(component),
+            },
+            _ => cleaned.push(component),
+        }
+    }
+
+    if cleaned.as_os_str().is_empty() {
+        cleaned.push(".");
+    }
+
+    cleaned
+}
+
+/// Serializes `path` as a string, replacing bytes that are not UTF-8 with `U+FFFD`.
+/// Plain `Path` serialization fails on those.
+pub fn serialize_lossy<P: AsRef<Path>, S: Serializer>(
+    path: &P,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&path.as_ref().to_string_lossy())
+}
+
+/// `path` (absolute) inside the folder `root`, e.g. `/home/user` in `/tmp/stage` becomes `/tmp/stage/home/user`.
+pub fn with_root(root: Option<&Path>, path: &Path) -> PathBuf {
+    match root {
+        Some(root) => root.join(path.strip_prefix("/").unwrap_or(path)),
+        None => path.to_path_buf(),
+    }
+}
+
+/// Prefix of module files that start with a `.` at the destination (`dot-bashrc` is linked as `.bashrc`).
+const DOT_PREFIX: &[u8] = b"dot-";
+
+/// Maps every `dot-` component of a module path to a leading `.` (`dot-config/dot-foo` becomes `.config/.foo`).
+pub fn from_dotfiles_path(path: &Path) -> PathBuf {
+    path.components()
+        .map(|component| {
+            let name = component.as_os_str().as_bytes();
+            match name.strip_prefix(DOT_PREFIX) {
+                Some(rest) if !rest.is_empty() => {
+                    let mut dotted = b".".to_vec();
+                    dotted.extend_from_slice(rest);
+                    OsString::from_vec(dotted)
+                }
+                _ => component.as_os_str().to_owned(),
+            }
+        })
+        .collect()
+}
+
+/// Maps every component with a leading `.` to `dot-` (`.config/.foo` becomes `dot-config/dot-foo`), the reverse of [`from_dotfiles_path`].
+pub fn to_dotfiles_path(path: &Path) -> PathBuf {
+    path.components()
+        .map(|component| match component {
+            Component::Normal(name) if name.as_bytes().starts_with(b".") => {
+                let mut undotted = DOT_PREFIX.to_vec();
+                undotted.extend_from_slice(&name.as_bytes()[1..]);
+                OsString::from_vec(undotted)
+            }
+            _ => component.as_os_str().to_owned(),
+        })
+        .collect()
+}
+
+/// The rule whose glob matches `path`, when several globs match the longest (most specific) one wins.
+/// `*` does not match `/`.
+pub fn get_matching_glob<'a, T>(
+    rules: &'a BTreeMap<String, T>,
+    path: &Path,
+) -> Result<Option<(&'a String, &'a T)>> {
+    let mut matching: Option<(&String, &T)> = None;
+
+    for (glob, value) in rules {
+        let matcher = GlobBuilder::new(glob)
+            .literal_separator(true)
+            .build()
+            .with_context(|| Error::Config(format!("Invalid glob \"{}\".", glob)))?
+            .compile_matcher();
+
+        if matcher.is_match(path) && matching.is_none_or(|(longest, _)| glob.len() >= longest.len())
+        {
+            matching = Some((glob, value));
+        }
     }
-    .clean();
 
-    Ok(absolute_path)
+    Ok(matching)
 }
@@ -0,0 +1,34 @@
+use crate::{error::Error, path};
+use anyhow::{bail, Context, Result};
+use std::{collections::BTreeMap, fs, os::unix::fs::PermissionsExt, path::Path};
+
+/// Parses an octal mode such as `700` or `0600`.
+pub fn parse_mode(mode: &str) -> Result<u32> {
+    let mode = u32::from_str_radix(mode.trim_start_matches("0o"), 8)
+        .with_context(|| Error::Config(format!("Invalid mode \"{}\".", mode)))?;
+
+    if mode > 0o7777 {
+        bail!(Error::Config(format!("Invalid mode \"{:o}\".", mode)));
+    }
+
+    Ok(mode)
+}
+
+/// Mode of the rule matching `path` (relative to the link folder).
+/// When several globs match the longest (most specific) one wins.
+pub fn get_mode(rules: &BTreeMap<String, String>, path: &Path) -> Result<Option<u32>> {
+    path::get_matching_glob(rules, path)
+        .context("Invalid \"permissions\".")?
+        .map(|(_, mode)| parse_mode(mode))
+        .transpose()
+}
+
+pub fn set_mode(path: &Path, mode: u32) -> Result<()> {
+    fs::set_permissions(path, fs::Permissions::from_mode(mode)).with_context(|| {
+        format!(
+            "Failed to set mode of \"{}\" to {:o}.",
+            path.display(),
+            mode
+        )
+    })
+}
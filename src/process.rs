@@ -0,0 +1,67 @@
+use crate::{
+    error::Error,
+    output::{self, Format},
+};
+use anyhow::{bail, Context, Result};
+use std::process::{Command, Stdio};
+
+/// Creates a command from a configured command line, e.g. `sudo pacman`.
+pub fn command(line: &str) -> Result<Command> {
+    let mut words = line.split_whitespace();
+
+    let program = match words.next() {
+        Some(program) => program,
+        None => bail!(Error::Config(format!("Empty command."))),
+    };
+
+    let mut command = Command::new(program);
+    command.args(words);
+
+    // Keep stdout for the JSON output
+    if output::format() == Format::Json {
+        command.stdout(Stdio::from(std::io::stderr()));
+    }
+
+    Ok(command)
+}
+
+/// Runs the command, failing if it does not succeed.
+pub fn run(command: &mut Command) -> Result<()> {
+    let status = command
+        .status()
+        .with_context(|| Error::Command(format!("Failed to run {:?}.", command)))?;
+
+    if !status.success() {
+        bail!(Error::Command(format!(
+            "{:?} failed ({}).",
+            command, status
+        )));
+    }
+
+    Ok(())
+}
+
+/// Runs the command and returns its stdout, failing if it does not succeed.
+pub fn read(command: &mut Command) -> Result<String> {
+    let stdout = read_bytes(command)?;
+
+    Ok(String::from_utf8_lossy(&stdout).into_owned())
+}
+
+/// Runs the command and returns its raw stdout, failing if it does not succeed.
+pub fn read_bytes(command: &mut Command) -> Result<Vec<u8>> {
+    let output = command
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .output()
+        .with_context(|| Error::Command(format!("Failed to run {:?}.", command)))?;
+
+    if !output.status.success() {
+        bail!(Error::Command(format!(
+            "{:?} failed ({}).",
+            command, output.status
+        )));
+    }
+
+    Ok(output.stdout)
+}
@@ -0,0 +1,136 @@
+use crate::{
+    config::SecretsConfig,
+    path::{self, absolute_path},
+    process,
+};
+use anyhow::{bail, Context, Result};
+use std::{
+    ffi::OsStr,
+    fs::{self, OpenOptions, Permissions},
+    io::Write,
+    os::unix::fs::{OpenOptionsExt, PermissionsExt},
+    path::{Path, PathBuf},
+};
+
+/// Extension of encrypted files in a module.
+const SECRET_EXTENSION: &str = "age";
+
+/// Whether the module file is an encrypted secret.
+pub fn is_secret(file: &Path) -> bool {
+    file.extension() == Some(OsStr::new(SECRET_EXTENSION))
+}
+
+/// Path of the decrypted secret, `.netrc.age` becomes `.netrc`.
+pub fn get_decrypted_path(file: &Path) -> PathBuf {
+    file.with_extension("")
+}
+
+/// Path of the encrypted secret, `.netrc` becomes `.netrc.age`.
+pub fn get_encrypted_path(file: &Path) -> PathBuf {
+    let mut encrypted = file.as_os_str().to_owned();
+    encrypted.push(".");
+    encrypted.push(SECRET_EXTENSION);
+    PathBuf::from(encrypted)
+}
+
+pub fn decrypt(secrets: &SecretsConfig, secret: &Path) -> Result<Vec<u8>> {
+    let mut age = process::command(secrets.get_age())?;
+    age.arg("--decrypt")
+        .arg("-i")
+        .arg(&secrets.identity)
+        .arg(secret);
+
+    process::read_bytes(&mut age)
+        .with_context(|| format!("Failed to decrypt \"{}\".", secret.display()))
+}
+
+pub fn encrypt(secrets: &SecretsConfig, file: &Path, secret: &Path) -> Result<()> {
+    let mut age = process::command(secrets.get_age())?;
+    age.arg("--encrypt")
+        .arg("-i")
+        .arg(&secrets.identity)
+        .arg("-o")
+        .arg(secret)
+        .arg(file);
+
+    process::run(&mut age).with_context(|| format!("Failed to encrypt \"{}\".", file.display()))
+}
+
+/// Writes a decrypted secret with `mode` (0600 so only the user can read it, unless configured otherwise).
+pub fn write_secret(path: &Path, data: &[u8], mode: u32) -> Result<()> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(mode)
+        .open(path)
+        .with_context(|| format!("Failed to write \"{}\".", path.display()))?;
+
+    // `mode` only applies to new files (and is subject to the umask)
+    file.set_permissions(Permissions::from_mode(mode))?;
+    file.write_all(data)?;
+
+    Ok(())
+}
+
+/// Encrypts `file` into the module folder `from`, keeping its path relative to the link folder `to`
+/// (with `.` files as `dot-` files if `dotfiles` is set).
+/// Returns the path of the encrypted file.
+pub fn add_secret(
+    secrets: &SecretsConfig,
+    from: &Path,
+    to: &Path,
+    file: &Path,
+    dotfiles: bool,
+) -> Result<PathBuf> {
+    let file = absolute_path(file)?;
+
+    let relative = match file.strip_prefix(to) {
+        Ok(relative) => relative.to_path_buf(),
+        Err(_) => match file.file_name() {
+            Some(name) => PathBuf::from(name),
+            None => bail!("Invalid secret \"{}\".", file.display()),
+        },
+    };
+
+    let relative = if dotfiles {
+        path::to_dotfiles_path(&relative)
+    } else {
+        relative
+    };
+
+    let secret = get_encrypted_path(&from.join(relative));
+    if let Some(parent) = secret.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    encrypt(secrets, &file, &secret)?;
+
+    Ok(secret)
+}
+
+/// Decrypts `secret` into a private temporary file, opens it in `$VISUAL`/`$EDITOR` and encrypts it back.
+/// Returns the decrypted secret before and after editing.
+pub fn edit_secret(secrets: &SecretsConfig, secret: &Path) -> Result<(Vec<u8>, Vec<u8>)> {
+    if !secret.is_file() {
+        bail!("\"{}\" does not exist.", secret.display());
+    }
+
+    // Created readable only by the user
+    let file = tempfile::Builder::new().prefix("kdot-secret-").tempfile()?;
+    let old = decrypt(secrets, secret)?;
+    write_secret(file.path(), &old, 0o600)?;
+
+    let editor = std::env::var("VISUAL")
+        .or_else(|_| std::env::var("EDITOR"))
+        .unwrap_or_else(|_| "vi".to_owned());
+
+    let mut edit = process::command(&editor)?;
+    edit.arg(file.path());
+    process::run(&mut edit)?;
+
+    let new = fs::read(file.path())?;
+    encrypt(secrets, file.path(), secret)?;
+
+    Ok((old, new))
+}
@@ -0,0 +1,60 @@
+use crate::{
+    config::ModuleConfig,
+    output::{self, Event},
+    process,
+};
+use anyhow::{Context, Result};
+
+/// Reloads the user units and enables and starts the module's services.
+pub fn enable_services(systemctl: &str, module: &ModuleConfig) -> Result<()> {
+    let services = match &module.services {
+        Some(services) if !services.is_empty() => services,
+        _ => return Ok(()),
+    };
+
+    // Pick up unit files that were just linked
+    let mut reload = process::command(systemctl)?;
+    reload.arg("--user").arg("daemon-reload");
+    process::run(&mut reload)?;
+
+    for service in services {
+        let mut enable = process::command(systemctl)?;
+        enable.arg("--user").arg("enable").arg("--now").arg(service);
+        process::run(&mut enable)
+            .with_context(|| format!("Failed to enable \"{}\" service.", service))?;
+
+        output::emit(
+            Some(&module.name),
+            Event::Service {
+                action: "enable",
+                unit: service,
+            },
+        );
+    }
+
+    Ok(())
+}
+
+/// Disables and stops the module's services.
+pub fn disable_services(systemctl: &str, module: &ModuleConfig) -> Result<()> {
+    for service in module.services.iter().flatten() {
+        let mut disable = process::command(systemctl)?;
+        disable
+            .arg("--user")
+            .arg("disable")
+            .arg("--now")
+            .arg(service);
+        process::run(&mut disable)
+            .with_context(|| format!("Failed to disable \"{}\" service.", service))?;
+
+        output::emit(
+            Some(&module.name),
+            Event::Service {
+                action: "disable",
+                unit: service,
+            },
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,126 @@
+use crate::filesystem::{FileType, Filesystem};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeSet,
+    ffi::OsString,
+    io,
+    os::unix::ffi::{OsStrExt, OsStringExt},
+    path::{Path, PathBuf},
+};
+
+/// A change the current run made to the filesystem, remembered to undo it if the run fails.
+#[derive(Debug, Clone)]
+pub enum Made {
+    /// Created the folder.
+    Folder(PathBuf),
+    /// Created the symlink `path` to `target`.
+    Link { path: PathBuf, target: PathBuf },
+    /// Decrypted a secret into the file.
+    File(PathBuf),
+    /// Removed the folder, which had `mode`.
+    RemovedFolder { path: PathBuf, mode: u32 },
+    /// Removed the symlink `path` to `target`.
+    RemovedLink { path: PathBuf, target: PathBuf },
+    /// Removed the file `path` decrypted from the `secret`.
+    RemovedFile {
+        path: PathBuf,
+        secret: PathBuf,
+        data: Vec<u8>,
+        mode: u32,
+    },
+}
+
+/// Mode of a new state file.
+const STATE_MODE: u32 = 0o644;
+
+/// What kdot remembers between runs, kept in the `state_file` of `kdot.json`.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct State {
+    /// Folders kdot created (absolute), the only ones it removes again.
+    #[serde(default, with = "stored_paths")]
+    pub folders: BTreeSet<PathBuf>,
+    /// What the current run changed, oldest first (never saved).
+    #[serde(skip)]
+    pub made: Vec<Made>,
+}
+
+impl State {
+    /// Loads the state from `file`, an empty state if it does not exist yet.
+    pub fn load(fs: &dyn Filesystem, file: &Path) -> Result<State> {
+        let data = match fs.read(file) {
+            Ok(data) => data,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(State::default()),
+            Err(error) => {
+                return Err(error)
+                    .with_context(|| format!("Failed to read state \"{}\".", file.display()))
+            }
+        };
+
+        serde_json::from_slice(&data)
+            .with_context(|| format!("Invalid state \"{}\".", file.display()))
+    }
+
+    pub fn save(&self, fs: &dyn Filesystem, file: &Path) -> Result<()> {
+        let data = serde_json::to_string_pretty(self)?;
+        // Keeps the mode of an existing file
+        let mode = match fs.file_type(file) {
+            Ok(FileType::File) => fs.mode(file)?,
+            _ => STATE_MODE,
+        };
+
+        fs.write(file, data.as_bytes(), mode)
+            .with_context(|| format!("Failed to write state \"{}\".", file.display()))
+    }
+
+    /// Remembers that kdot created the folder `path`.
+    pub fn add_folder(&mut self, path: &Path) {
+        self.folders.insert(path.to_path_buf());
+    }
+}
+
+/// A path in the state file: a string if it is UTF-8, its raw bytes otherwise.
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum StoredPath {
+    Text(String),
+    Bytes(Vec<u8>),
+}
+
+impl From<&PathBuf> for StoredPath {
+    fn from(path: &PathBuf) -> Self {
+        match path.to_str() {
+            Some(text) => StoredPath::Text(text.to_string()),
+            None => StoredPath::Bytes(path.as_os_str().as_bytes().to_vec()),
+        }
+    }
+}
+
+impl From<StoredPath> for PathBuf {
+    fn from(path: StoredPath) -> Self {
+        match path {
+            StoredPath::Text(text) => PathBuf::from(text),
+            StoredPath::Bytes(bytes) => PathBuf::from(OsString::from_vec(bytes)),
+        }
+    }
+}
+
+mod stored_paths {
+    use super::StoredPath;
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::{collections::BTreeSet, path::PathBuf};
+
+    pub fn serialize<S: Serializer>(
+        paths: &BTreeSet<PathBuf>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(paths.iter().map(StoredPath::from))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<BTreeSet<PathBuf>, D::Error> {
+        let paths = Vec::<StoredPath>::deserialize(deserializer)?;
+        Ok(paths.into_iter().map(PathBuf::from).collect())
+    }
+}
@@ -1,132 +1,396 @@
+use crate::{
+    config::{SecretsConfig, Symlinks, WalkErrors},
+    error::Error,
+    filesystem::{self, FileType, Filesystem},
+    ignores,
+    output::{self, Event, FileState},
+    path, permissions, secrets,
+    state::{Made, State},
+};
 use anyhow::{bail, Context, Result};
 use pathdiff::diff_paths;
+use serde::Serialize;
 use std::{
-    collections::HashSet,
-    fs::{self},
-    os::unix::fs as unixfs,
+    cell::RefCell,
+    collections::{BTreeMap, HashSet},
+    fmt, io,
+    path::{Path, PathBuf},
 };
-use std::{fs::canonicalize, path::PathBuf};
-use walkdir::WalkDir;
-
-/// Creates a file symlink.
-// pub fn link_file(from: &PathBuf, to: &PathBuf) -> Result<()> {
-//     unixfs::symlink(to, from).with_context(|| {
-//         format!(
-//             "Failed to symlink \"{}\" -> \"{}\"",
-//             from.as_os_str().to_str().unwrap(),
-//             to.as_os_str().to_str().unwrap()
-//         )
-//     })?;
-
-//     Ok(())
-// }
-
-/// Remove a file symlink.
-pub fn unlink_file(path: &PathBuf) -> Result<()> {
-    fs::remove_file(path).with_context(|| {
-        format!(
-            "Failed to remove \"{}\"",
-            path.as_os_str().to_str().unwrap()
-        )
-    })?;
 
-    Ok(())
+/// How the files of a module are linked.
+#[derive(Debug, Clone, Copy)]
+pub struct LinkOptions<'a> {
+    /// Name of the module, for reporting.
+    pub module: &'a str,
+    /// Used to decrypt secrets (`.age` files), which are never linked.
+    pub secrets: Option<&'a SecretsConfig>,
+    /// Glob (relative to the link folder) to mode of created folders and decrypted files.
+    pub permissions: Option<&'a BTreeMap<String, String>>,
+    /// Extra ignore patterns (gitignore syntax), on top of the defaults and `.kdotignore`.
+    pub ignore: Option<&'a Vec<String>>,
+    /// What to do with module entries that cannot be linked.
+    pub walk_errors: WalkErrors,
+    /// How symlinks inside the module are linked.
+    pub symlinks: Symlinks,
+    /// Module file (or glob) to the path it is linked to, outside of the link folder.
+    pub files: Option<&'a BTreeMap<String, String>>,
+    /// Links `dot-` module files as `.` files.
+    pub dotfiles: bool,
+    /// Folder the `files` destinations are inside of (`--root`).
+    pub root: Option<&'a Path>,
+    /// Filesystem the module is linked on.
+    pub fs: &'a dyn Filesystem,
+    /// Remembers the folders kdot creates, so unlinking only removes those, and what the run changed.
+    pub state: &'a RefCell<State>,
+}
+
+impl LinkOptions<'_> {
+    /// Where a module file ends up, relative to the link folder.
+    fn get_target_path(&self, file: &Path) -> PathBuf {
+        let file = if self.dotfiles {
+            path::from_dotfiles_path(file)
+        } else {
+            file.to_path_buf()
+        };
+
+        if secrets::is_secret(&file) {
+            secrets::get_decrypted_path(&file)
+        } else {
+            file
+        }
+    }
+
+    /// Where the module `file` ends up, in the link folder `from` unless it is overridden by `files`.
+    fn get_destination(&self, from: &Path, file: &Path) -> Result<PathBuf> {
+        let target = self.get_target_path(file);
+
+        let destination = match self.files {
+            Some(files) => path::get_matching_glob(files, file)
+                .with_context(|| format!("Invalid \"files\" of \"{}\" module.", self.module))?,
+            None => None,
+        };
+
+        Ok(match destination {
+            // A folder to link the file into
+            Some((_, destination)) if destination.ends_with('/') => {
+                path::with_root(self.root, &path::absolute_path(destination)?)
+                    .join(target.file_name().unwrap_or_default())
+            }
+            Some((_, destination)) => {
+                path::with_root(self.root, &path::absolute_path(destination)?)
+            }
+            None => from.join(target),
+        })
+    }
+
+    /// Mode wanted for `path`, relative to the link folder.
+    fn get_mode(&self, path: &Path) -> Result<Option<u32>> {
+        match self.permissions {
+            Some(rules) => permissions::get_mode(rules, path),
+            None => Ok(None),
+        }
+    }
 }
 
-/// Returns the absolute path of wanted from file, the to file symbolic file, and (if to file is a symlink) the real to path (resolved module file symlink).
+/// Returns the absolute path of wanted from file, the to file symbolic file, and the path the link points to.
+/// The link points to the resolved module file, or to the module file itself when symlinks are preserved.
 /// ## Assumes
 /// - `to_folder` is absolute
 /// - `to_file` is absolute (ideally includes `to_folder` as parent otherwise will error)
 /// - `from` is relative to the kdot file
 fn get_paths(
+    options: &LinkOptions,
     to_folder: &PathBuf,
     to_file: &PathBuf,
-    from: &PathBuf,
+    from: &Path,
 ) -> Result<(PathBuf, PathBuf, PathBuf)> {
-    // Link to real file (since canonicalize follows symlink)
-    let to_real_abs = canonicalize(&to_file)?;
     // Link to file that is a symbolic link
-    let to_sym_abs = crate::path::absolute_path(&to_file)?;
+    let to_sym_abs = path::absolute_path(to_file)?;
+    let to_real_abs = match options.symlinks {
+        Symlinks::Preserve => to_sym_abs.clone(),
+        // Link to real file (since canonicalize follows symlink)
+        Symlinks::Follow | Symlinks::Reject => options.fs.canonicalize(to_file)?,
+    };
 
     let diff = {
-        if let Some(diff) = diff_paths(&to_sym_abs, &to_folder) {
+        if let Some(diff) = diff_paths(&to_sym_abs, to_folder) {
             diff
         } else {
             bail!("Invalid path difference.");
         }
     };
 
-    let mut relative_from = from.clone();
-    relative_from.push(&diff);
+    let relative_from = options.get_destination(from, &diff)?;
 
     // Create the folder of the file (if it does not already exist)
     let relative_from_parent = relative_from.parent().unwrap();
-    if !relative_from_parent.exists() {
-        fs::create_dir_all(relative_from_parent)?;
-        info!(
-            "Created \"{}\"",
-            relative_from_parent.as_os_str().to_str().unwrap()
+    create_folders(options, from, relative_from_parent)?;
+
+    Ok((relative_from, to_sym_abs, to_real_abs))
+}
+
+/// Creates `folder` and its missing parents (inside the link folder `from`), applying their permission rules.
+fn create_folders(options: &LinkOptions, from: &Path, folder: &Path) -> Result<()> {
+    if options.fs.exists(folder) {
+        return Ok(());
+    }
+
+    if let Some(parent) = folder.parent() {
+        create_folders(options, from, parent)?;
+    }
+
+    options.fs.create_dir(folder)?;
+    let mut state = options.state.borrow_mut();
+    state.add_folder(folder);
+    state.made.push(Made::Folder(folder.to_path_buf()));
+    drop(state);
+    output::emit(Some(options.module), Event::Mkdir { path: folder });
+
+    if let Ok(relative) = folder.strip_prefix(from) {
+        if let Some(mode) = options.get_mode(relative)? {
+            options.fs.set_mode(folder, mode)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Folders (inside the link folder `from`) and decrypted secrets of the module whose mode does not match their permission rule.
+/// Returns the path, the wanted mode and the current mode.
+pub fn get_wrong_modes(
+    options: &LinkOptions,
+    from: &Path,
+    to: &Path,
+) -> Result<Vec<(PathBuf, u32, u32)>> {
+    let mut wanted: BTreeMap<PathBuf, u32> = BTreeMap::new();
+
+    for (file, file_type) in get_relative_files(options, to)? {
+        let destination = options.get_destination(from, &file)?;
+        // Rules only apply inside the link folder
+        let relative = destination.strip_prefix(from).ok();
+        // Module folders have a rule of their own
+        let skip = if file_type == FileType::Dir { 0 } else { 1 };
+
+        for folder in relative
+            .iter()
+            .flat_map(|relative| relative.ancestors().skip(skip))
+        {
+            if folder.as_os_str().is_empty() {
+                break;
+            }
+            if let Some(mode) = options.get_mode(folder)? {
+                wanted.insert(from.join(folder), mode);
+            }
+        }
+
+        if secrets::is_secret(&file) {
+            let mode = match relative {
+                Some(relative) => options.get_mode(relative)?,
+                None => None,
+            };
+            wanted.insert(destination, mode.unwrap_or(SECRET_MODE));
+        }
+    }
+
+    let mut wrong = Vec::new();
+
+    for (path, mode) in wanted {
+        // Missing files are reported by their state
+        if let Ok(current) = options.fs.mode(&path) {
+            if current != mode {
+                wrong.push((path, mode, current));
+            }
+        }
+    }
+
+    Ok(wrong)
+}
+
+/// Whether `path` is a symlink resolving to `target`.
+fn is_linked_to(fs: &dyn Filesystem, path: &Path, target: &Path) -> bool {
+    fs.read_link(path).is_ok() && fs.canonicalize(path).ok() == fs.canonicalize(target).ok()
+}
+
+/// Mode of decrypted secrets without a permission rule.
+const SECRET_MODE: u32 = 0o600;
+
+/// Decrypts the secret `to` into `from` (inside the link folder `root`), unless it already is.
+fn decrypt_file(options: &LinkOptions, root: &Path, from: &Path, to: &Path) -> Result<()> {
+    let module = options.module;
+    let secrets = match options.secrets {
+        Some(secrets) => secrets,
+        None => bail!(Error::Config(format!(
+            "\"{}\" is a secret but no \"secrets\" are configured in kdot.json.",
+            to.display()
+        ))),
+    };
+
+    let data = secrets::decrypt(secrets, to)?;
+
+    if let Ok(file_type) = options.fs.file_type(from) {
+        if file_type == FileType::File && options.fs.read(from)? == data {
+            output::emit(
+                Some(module),
+                Event::Skip {
+                    path: from,
+                    reason: "already decrypted",
+                },
+            );
+            return Ok(());
+        }
+
+        output::emit(
+            Some(module),
+            Event::Conflict {
+                path: from,
+                target: to,
+            },
         );
+        bail!(Error::Conflict(from.to_path_buf()));
     }
 
-    Ok((relative_from, to_sym_abs, to_real_abs))
+    let mode = match from.strip_prefix(root) {
+        Ok(relative) => options.get_mode(relative)?,
+        Err(_) => None,
+    };
+    options.fs.write(from, &data, mode.unwrap_or(SECRET_MODE))?;
+    options
+        .state
+        .borrow_mut()
+        .made
+        .push(Made::File(from.to_path_buf()));
+    output::emit(
+        Some(module),
+        Event::Decrypt {
+            path: from,
+            target: to,
+        },
+    );
+
+    Ok(())
+}
+
+/// Updates the decrypted copy of the module secret `secret` (inside the module folder `to`) in the link folder `from`
+/// from `old` to `new`, if it still holds `old`. Copies that were changed since are left for unlinking to report.
+pub fn update_decrypted(
+    options: &LinkOptions,
+    from: &Path,
+    to: &Path,
+    secret: &Path,
+    old: &[u8],
+    new: &[u8],
+) -> Result<()> {
+    let relative = secret.strip_prefix(to)?;
+    let path = options.get_destination(from, relative)?;
+
+    if old == new
+        || options.fs.file_type(&path).ok() != Some(FileType::File)
+        || options.fs.read(&path)? != old
+    {
+        return Ok(());
+    }
+
+    let mode = options.fs.mode(&path)?;
+    options.fs.write(&path, new, mode)?;
+    output::emit(
+        Some(options.module),
+        Event::Decrypt {
+            path: &path,
+            target: secret,
+        },
+    );
+
+    Ok(())
 }
 
 /// Creates a folder symlink.
-pub fn link_folder(from: &PathBuf, to: &PathBuf, recursive: bool) -> Result<()> {
+pub fn link_folder(
+    options: &LinkOptions,
+    from: &PathBuf,
+    to: &PathBuf,
+    recursive: bool,
+) -> Result<()> {
+    let module = options.module;
+    let root = from;
+
     if recursive {
-        // Walk the files and symlink if file or create directory
-        let walker = WalkDir::new(&to).into_iter();
+        // Walk the files and symlink them, creating their folders
+        let files = get_module_files(options, to)?;
 
-        let full_to_path = canonicalize(&to)?;
+        let full_to_path = options.fs.canonicalize(to)?;
 
         debug!("Recursivly linking.");
 
-        for entry in walker {
-            let entry = entry.unwrap();
-            let file = entry.path();
+        for (file, file_type) in files {
+            if file_type == FileType::Dir {
+                let destination = options.get_destination(from, file.strip_prefix(to)?)?;
+                link_empty_folder(options, from, &destination, &file)?;
+                continue;
+            }
 
-            if file.is_dir() && !file.exists() {
-                debug!(
-                    "\"{}\" directory does not exist. Creating it.",
-                    file.as_os_str().to_str().unwrap()
-                );
+            // Handles files in module that link to other files in module (module/a.txt -> module/b.txt)
+            let (from, to_display, to) = get_paths(options, &full_to_path, &file, from)?;
 
-                fs::create_dir_all(file)?;
-            } else if file.is_file() {
-                // Handles files in module that link to other files in module (module/a.txt -> module/b.txt)
-                let (from, to_display, to) = get_paths(&full_to_path, &file.to_path_buf(), &from)?;
+            if secrets::is_secret(&to_display) {
+                decrypt_file(options, root, &from, &to_display)?;
+                continue;
+            }
 
-                info!(
-                    "Linking \"{}\" -> \"{}\"",
-                    from.as_os_str().to_str().unwrap(),
-                    to_display.as_os_str().to_str().unwrap()
+            if is_linked_to(options.fs, &from, &to) {
+                output::emit(
+                    Some(module),
+                    Event::Skip {
+                        path: &from,
+                        reason: "already linked",
+                    },
                 );
+                continue;
+            }
 
-                // link_file(&relative_from, file)?;
-                // TODO: remove below infavor of generic impl
-                unixfs::symlink(&to, &from).with_context(|| {
-                    format!(
-                        "Failed to symlink \"{}\" -> \"{}\"{}",
-                        from.as_os_str().to_str().unwrap(),
-                        to.as_os_str().to_str().unwrap(),
-                        if to_display != to {
-                            " (symbolic module link)"
-                        } else {
-                            ""
-                        }
-                    )
-                })?;
+            if options.fs.file_type(&from).is_ok() {
+                output::emit(
+                    Some(module),
+                    Event::Conflict {
+                        path: &from,
+                        target: &to_display,
+                    },
+                );
+                bail!(Error::Conflict(from));
             }
+
+            options.fs.symlink(&to, &from)?;
+            options.state.borrow_mut().made.push(Made::Link {
+                path: from.clone(),
+                target: to.clone(),
+            });
+
+            output::emit(
+                Some(module),
+                Event::Link {
+                    path: &from,
+                    target: &to_display,
+                },
+            );
         }
     } else {
         debug!("Symlinking folder directly.");
 
-        if from.exists() {
-            bail!("\"from\" path already exists.");
+        if options.fs.exists(from) {
+            bail!(Error::Conflict(from.clone()));
         }
 
-        unixfs::symlink(&to, &from).with_context(|| format!("Failed to symlink."))?;
+        options.fs.symlink(to, from)?;
+        options.state.borrow_mut().made.push(Made::Link {
+            path: from.clone(),
+            target: to.clone(),
+        });
+        output::emit(
+            Some(module),
+            Event::Link {
+                path: from,
+                target: to,
+            },
+        );
     }
 
     debug!("Done linking folders.");
@@ -134,35 +398,406 @@ pub fn link_folder(from: &PathBuf, to: &PathBuf, recursive: bool) -> Result<()>
     Ok(())
 }
 
-/// Gets all files relative from `folder`
-fn get_relative_files(folder: &PathBuf) -> HashSet<PathBuf> {
-    let mut relative_files = HashSet::new();
+/// What a module entry is linked as.
+enum Entry {
+    /// Linked on its own.
+    File,
+    /// A folder, created at the destination if it is empty.
+    Folder,
+    /// A symlinked folder whose entries are linked (and created like a folder), with its resolved path.
+    Walk(PathBuf),
+}
+
+/// What the module entry `path` of `file_type` is linked as, fails if it cannot be linked.
+/// `followed` are the resolved folders walked to reach `path`.
+fn get_entry(
+    options: &LinkOptions,
+    path: &Path,
+    file_type: io::Result<FileType>,
+    followed: &[PathBuf],
+) -> Result<Entry> {
+    let file_type = match file_type {
+        Ok(file_type) => file_type,
+        Err(error) => {
+            return Err(Error::from_io(error, path))
+                .with_context(|| format!("Failed to read \"{}\".", path.display()))
+        }
+    };
+
+    let resolved_type = match file_type {
+        FileType::Symlink => options
+            .fs
+            .resolved_type(path)
+            .with_context(|| format!("\"{}\" is a broken or looping symlink.", path.display()))?,
+        file_type => file_type,
+    };
+
+    Ok(match (file_type, resolved_type) {
+        (_, FileType::Other) => bail!(
+            "\"{}\" is a socket, FIFO or device, which cannot be linked.",
+            path.display()
+        ),
+        (FileType::Symlink, FileType::Dir) if options.symlinks == Symlinks::Preserve => Entry::File,
+        (FileType::Symlink, FileType::Dir) => {
+            // Walking a folder that contains the symlink would never end
+            let folder = options.fs.canonicalize(path)?;
+            let parent = options.fs.canonicalize(path.parent().unwrap_or(path))?;
+            if std::iter::once(&parent)
+                .chain(followed)
+                .any(|walked| walked.starts_with(&folder))
+            {
+                bail!("\"{}\" is a looping symlink.", path.display());
+            }
+            Entry::Walk(folder)
+        }
+        (FileType::Dir, _) => Entry::Folder,
+        _ => Entry::File,
+    })
+}
+
+/// Walks the module folder `to` for the files to link and the empty folders to create, skipping ignored ones.
+/// Entries that cannot be linked (unreadable folders, special files, broken or looping symlinks) fail or are skipped with a warning, as `walk_errors` says.
+/// Symlinks fail if the module rejects them.
+fn get_module_files(options: &LinkOptions, to: &Path) -> Result<Vec<(PathBuf, FileType)>> {
+    let ignore = ignores::build_ignore(options.fs, to, options.ignore)?;
+    let filter = |path: &Path, file_type| match path.strip_prefix(to) {
+        Ok(relative) => !ignores::is_ignored(&ignore, relative, file_type == FileType::Dir),
+        Err(_) => true,
+    };
+
+    let mut entries = Vec::new();
+    // The module folder and the symlinked folders in it, with the folders followed to reach them
+    let mut folders = vec![(
+        to.to_path_buf(),
+        vec![options
+            .fs
+            .canonicalize(to)
+            .unwrap_or_else(|_| to.to_path_buf())],
+    )];
+
+    while let Some((folder, followed)) = folders.pop() {
+        for (path, file_type) in filesystem::walk(options.fs, &folder, &filter) {
+            if options.symlinks == Symlinks::Reject && matches!(file_type, Ok(FileType::Symlink)) {
+                bail!(
+                    "\"{}\" is a symlink, which \"{}\" module rejects.",
+                    path.display(),
+                    options.module
+                );
+            }
+
+            let error = match get_entry(options, &path, file_type, &followed) {
+                Ok(Entry::File) => {
+                    entries.push((path, FileType::File));
+                    continue;
+                }
+                Ok(Entry::Folder) => {
+                    entries.push((path, FileType::Dir));
+                    continue;
+                }
+                Ok(Entry::Walk(resolved)) => {
+                    let mut followed = followed.clone();
+                    followed.push(resolved);
+                    entries.push((path.clone(), FileType::Dir));
+                    folders.push((path, followed));
+                    continue;
+                }
+                Err(error) => error,
+            };
+
+            match options.walk_errors {
+                WalkErrors::Fail => {
+                    return Err(error)
+                        .with_context(|| format!("Failed to walk \"{}\" module.", options.module))
+                }
+                WalkErrors::Warn => {
+                    warn!("Skipping: {:#}", error);
+                    // An unreadable folder is not an empty one
+                    entries.retain(|(entry, _)| *entry != path);
+                }
+            }
+        }
+    }
+
+    // Depth first order, so the entries of a folder come right after it
+    entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    // Folders with entries are created along with their files
+    let empty: Vec<bool> = entries
+        .iter()
+        .enumerate()
+        .map(|(index, (path, _))| {
+            entries
+                .get(index + 1)
+                .is_none_or(|(next, _)| !next.starts_with(path))
+        })
+        .collect();
+
+    Ok(entries
+        .into_iter()
+        .zip(empty)
+        .filter(|((_, file_type), empty)| *file_type != FileType::Dir || *empty)
+        .map(|(entry, _)| entry)
+        .collect())
+}
+
+/// Gets all (not ignored) files and empty folders relative from `folder`
+fn get_relative_files(options: &LinkOptions, folder: &Path) -> Result<Vec<(PathBuf, FileType)>> {
+    Ok(get_module_files(options, folder)?
+        .into_iter()
+        .map(|(file, file_type)| (diff_paths(&file, folder).unwrap(), file_type))
+        .collect())
+}
+
+/// Creates `path` (inside the link folder `from`) for the empty module folder `target`, unless it exists.
+fn link_empty_folder(options: &LinkOptions, from: &Path, path: &Path, target: &Path) -> Result<()> {
+    match options.fs.resolved_type(path) {
+        Ok(FileType::Dir) => {
+            output::emit(
+                Some(options.module),
+                Event::Skip {
+                    path,
+                    reason: "already exists",
+                },
+            );
+            Ok(())
+        }
+        Ok(_) => {
+            output::emit(Some(options.module), Event::Conflict { path, target });
+            bail!(Error::Conflict(path.to_path_buf()))
+        }
+        // A broken symlink
+        Err(_) if options.fs.file_type(path).is_ok() => {
+            output::emit(Some(options.module), Event::Conflict { path, target });
+            bail!(Error::Conflict(path.to_path_buf()))
+        }
+        Err(_) => create_folders(options, from, path),
+    }
+}
+
+/// How much of a module folder is linked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LinkStatus {
+    /// Every file is linked.
+    Linked,
+    /// Some files are linked.
+    Partial,
+    /// No files are linked.
+    Unlinked,
+    /// A file is in the way of a link.
+    Conflict,
+}
+
+impl fmt::Display for LinkStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let status = match self {
+            LinkStatus::Linked => "linked",
+            LinkStatus::Partial => "partial",
+            LinkStatus::Unlinked => "unlinked",
+            LinkStatus::Conflict => "conflict",
+        };
+
+        write!(f, "{}", status)
+    }
+}
+
+/// Checks every file (and empty folder) in `to` for a link (or folder) in `from`.
+/// Returns the link location, the module file and its state, sorted by link location.
+pub fn file_states(
+    options: &LinkOptions,
+    from: &Path,
+    to: &Path,
+) -> Result<Vec<(PathBuf, PathBuf, FileState)>> {
+    Ok(entry_states(options, from, to)?
+        .into_iter()
+        .map(|(path, target, state, _)| (path, target, state))
+        .collect())
+}
+
+/// [`file_states`] with the type of the module entry, a file or an (empty) folder.
+fn entry_states(
+    options: &LinkOptions,
+    from: &Path,
+    to: &Path,
+) -> Result<Vec<(PathBuf, PathBuf, FileState, FileType)>> {
+    let mut states = get_relative_files(options, to)?
+        .into_iter()
+        .map(|(file, file_type)| {
+            let from_file = options.get_destination(from, &file)?;
+            let to_file = to.join(&file);
+
+            let state = match options.fs.file_type(&from_file) {
+                Err(_) => FileState::Unlinked,
+                Ok(_) if file_type == FileType::Dir => match options.fs.resolved_type(&from_file) {
+                    Ok(FileType::Dir) => FileState::Linked,
+                    _ => FileState::Conflict,
+                },
+                // Not decrypted here, that could prompt for a passphrase
+                Ok(FileType::File) if secrets::is_secret(&file) => FileState::Linked,
+                Ok(_) if is_linked_to(options.fs, &from_file, &to_file) => FileState::Linked,
+                Ok(_) => FileState::Conflict,
+            };
 
-    let walker = WalkDir::new(&folder).into_iter();
+            Ok((from_file, to_file, state, file_type))
+        })
+        .collect::<Result<Vec<_>>>()?;
 
-    for entry in walker {
-        // Ignore invalid permissioned files
-        if let Ok(entry) = entry {
-            let file = entry.path();
+    states.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(states)
+}
+
+/// How much of the files (from [`file_states`]) are linked.
+pub fn get_link_status(states: &[(PathBuf, PathBuf, FileState)]) -> LinkStatus {
+    let count = |wanted: FileState| states.iter().filter(|(_, _, s)| *s == wanted).count();
 
-            if file.is_file() {
-                let relative_to_folder = diff_paths(file.to_owned(), &folder).unwrap();
-                relative_files.insert(relative_to_folder);
+    if count(FileState::Conflict) > 0 {
+        return LinkStatus::Conflict;
+    }
+
+    match (count(FileState::Linked), count(FileState::Unlinked)) {
+        (0, _) => LinkStatus::Unlinked,
+        (_, 0) => LinkStatus::Linked,
+        _ => LinkStatus::Partial,
+    }
+}
+
+/// A change linking a module would make.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "change", rename_all = "lowercase")]
+pub enum Change {
+    /// Create the folder `path`.
+    Mkdir {
+        #[serde(serialize_with = "path::serialize_lossy")]
+        path: PathBuf,
+    },
+    /// Symlink `path` to the module file `target`.
+    Link {
+        #[serde(serialize_with = "path::serialize_lossy")]
+        path: PathBuf,
+        #[serde(serialize_with = "path::serialize_lossy")]
+        target: PathBuf,
+    },
+    /// Decrypt the secret `target` into `path`.
+    Decrypt {
+        #[serde(serialize_with = "path::serialize_lossy")]
+        path: PathBuf,
+        #[serde(serialize_with = "path::serialize_lossy")]
+        target: PathBuf,
+    },
+    /// `path` is in the way of `target`, linking would fail.
+    Conflict {
+        #[serde(serialize_with = "path::serialize_lossy")]
+        path: PathBuf,
+        #[serde(serialize_with = "path::serialize_lossy")]
+        target: PathBuf,
+    },
+}
+
+/// The changes [`link_folder`] would make to link the files in `to` into `from`, without making them.
+pub fn plan_folder(options: &LinkOptions, from: &Path, to: &Path) -> Result<Vec<Change>> {
+    let mut changes = Vec::new();
+    let mut planned_folders = HashSet::new();
+
+    for (path, target, state, file_type) in entry_states(options, from, to)? {
+        match state {
+            FileState::Linked => {}
+            FileState::Conflict => changes.push(Change::Conflict { path, target }),
+            FileState::Unlinked => {
+                // An empty folder is created itself
+                let skip = if file_type == FileType::Dir { 0 } else { 1 };
+                let mut missing: Vec<_> = path
+                    .ancestors()
+                    .skip(skip)
+                    .take_while(|folder| !options.fs.exists(folder))
+                    .filter(|folder| !planned_folders.contains(*folder))
+                    .map(Path::to_path_buf)
+                    .collect();
+                missing.reverse();
+
+                for folder in missing {
+                    planned_folders.insert(folder.clone());
+                    changes.push(Change::Mkdir { path: folder });
+                }
+
+                if file_type == FileType::Dir {
+                    continue;
+                } else if secrets::is_secret(&target) {
+                    changes.push(Change::Decrypt { path, target });
+                } else {
+                    changes.push(Change::Link { path, target });
+                }
             }
         }
     }
 
-    relative_files
+    Ok(changes)
+}
+
+/// Removes the empty module folder `path`, if kdot created it and nothing was added to it since.
+fn unlink_empty_folder(options: &LinkOptions, path: &Path) -> Result<()> {
+    let reason = if !options.state.borrow().folders.contains(path) {
+        "not created by kdot"
+    } else if !options
+        .fs
+        .read_dir(path)
+        .is_ok_and(|entries| entries.is_empty())
+    {
+        "not empty"
+    } else {
+        return remove_created_folders(options, path);
+    };
+
+    output::emit(Some(options.module), Event::Skip { path, reason });
+    Ok(())
+}
+
+/// Removes `folder` and then its parents, as long as kdot created them and they are empty.
+/// Folders that were there before kdot are never in the state, so they are never removed.
+fn remove_created_folders(options: &LinkOptions, folder: &Path) -> Result<()> {
+    for folder in folder.ancestors() {
+        let empty = options
+            .fs
+            .read_dir(folder)
+            .is_ok_and(|entries| entries.is_empty());
+
+        if !empty || !options.state.borrow().folders.contains(folder) {
+            break;
+        }
+
+        let mode = options.fs.mode(folder)?;
+        options.fs.remove_dir(folder)?;
+        let mut state = options.state.borrow_mut();
+        state.folders.remove(folder);
+        state.made.push(Made::RemovedFolder {
+            path: folder.to_path_buf(),
+            mode,
+        });
+        drop(state);
+        output::emit(Some(options.module), Event::Remove { path: folder });
+    }
+
+    Ok(())
 }
 
 /// Remove a folder symlink.
-pub fn unlink_folder(from: &PathBuf, to: &PathBuf, recursive: bool) -> Result<()> {
-    if !from.exists() {
+/// Files kdot did not link (or changed decrypted secrets) are left in place, failing with [`Error::NotOwned`] once the rest is unlinked.
+pub fn unlink_folder(
+    options: &LinkOptions,
+    from: &PathBuf,
+    to: &Path,
+    recursive: bool,
+) -> Result<()> {
+    let module = options.module;
+
+    // Its files are skipped below, files linked elsewhere by `files` are still unlinked
+    if !options.fs.exists(from) {
         warn!("info: from does not exist!");
-    } else if recursive {
+    }
+
+    if recursive {
         debug!("Unlinking recursivly.");
 
-        let relative_files_to = get_relative_files(&to);
+        let relative_files_to = get_relative_files(options, to)?;
 
         debug!("Unlinking: {:?}", relative_files_to);
 
@@ -171,38 +806,713 @@ pub fn unlink_folder(from: &PathBuf, to: &PathBuf, recursive: bool) -> Result<()
             return Ok(());
         }
 
-        for file in relative_files_to
-            .into_iter()
-            // Filter out files that don't exist in `from`
-            .filter(|file| {
-                let from_file = {
-                    let mut f = from.clone();
-                    f.push(file);
-                    f
+        let mut not_owned = None;
+
+        for (file, file_type) in relative_files_to {
+            let from_file = options.get_destination(from, &file)?;
+            let to_file = to.join(&file);
+
+            // Nothing to unlink, but its folders may be left empty
+            if !options.fs.exists(&from_file) {
+                if let Some(parent) = from_file.parent() {
+                    remove_created_folders(options, parent)?;
+                }
+                continue;
+            }
+
+            if file_type == FileType::Dir {
+                unlink_empty_folder(options, &from_file)?;
+                continue;
+            }
+
+            // What undoing the removal takes, if kdot owns the file
+            let (made, reason) = if secrets::is_secret(&file) {
+                let decrypted = match options.secrets {
+                    Some(secrets) if options.fs.file_type(&from_file)? == FileType::File => {
+                        let data = options.fs.read(&from_file)?;
+                        if data == secrets::decrypt(secrets, &to_file)? {
+                            Some(Made::RemovedFile {
+                                mode: options.fs.mode(&from_file)?,
+                                path: from_file.clone(),
+                                secret: to_file.clone(),
+                                data,
+                            })
+                        } else {
+                            None
+                        }
+                    }
+                    _ => None,
+                };
+                (decrypted, "changed since it was decrypted")
+            } else {
+                let linked = if is_linked_to(options.fs, &from_file, &to_file) {
+                    Some(Made::RemovedLink {
+                        path: from_file.clone(),
+                        target: options.fs.read_link(&from_file)?,
+                    })
+                } else {
+                    None
                 };
+                (linked, "not linked to the module")
+            };
 
-                from_file.exists()
-            })
-            // Make path absolute
-            .map(|file| from.join(&file))
-        {
-            info!("Unlinking \"{}\"", file.as_os_str().to_str().unwrap());
-            unlink_file(&file)?;
+            let made = match made {
+                Some(made) => made,
+                None => {
+                    output::emit(
+                        Some(module),
+                        Event::Skip {
+                            path: &from_file,
+                            reason,
+                        },
+                    );
+                    not_owned.get_or_insert(from_file);
+                    continue;
+                }
+            };
+
+            options.fs.remove_file(&from_file)?;
+            options.state.borrow_mut().made.push(made);
+            output::emit(Some(module), Event::Remove { path: &from_file });
+
+            if let Some(parent) = from_file.parent() {
+                remove_created_folders(options, parent)?;
+            }
         }
-    } else {
+
+        if let Some(path) = not_owned {
+            bail!(Error::NotOwned(path));
+        }
+    } else if options.fs.exists(from) {
         debug!("Unlinking root folder.");
 
-        if fs::read_link(from).is_ok() {
+        if let Ok(target) = options.fs.read_link(from) {
             // Is symoblic
-            unlink_file(from)?;
+            options.fs.remove_file(from)?;
+            options.state.borrow_mut().made.push(Made::RemovedLink {
+                path: from.clone(),
+                target,
+            });
+            output::emit(Some(module), Event::Remove { path: from });
         } else {
-            bail!(
-                "Expected \"{}\" to be symobolically linked to \"{}\" but it is not.",
-                from.as_os_str().to_str().unwrap(),
-                to.as_os_str().to_str().unwrap()
-            );
+            return Err(Error::NotOwned(from.clone())).with_context(|| {
+                format!(
+                    "Expected \"{}\" to be symobolically linked to \"{}\" but it is not.",
+                    from.display(),
+                    to.display()
+                )
+            });
+        }
+    }
+
+    Ok(())
+}
+
+/// Undoes what the current run changed (the `made` of the state), newest first.
+/// Changes made to the files since are left alone, like everything the run did not change.
+pub fn undo(options: &LinkOptions) -> Result<()> {
+    let module = options.module;
+    let made = std::mem::take(&mut options.state.borrow_mut().made);
+
+    for made in made.into_iter().rev() {
+        match made {
+            Made::Folder(path) => {
+                let empty = options
+                    .fs
+                    .read_dir(&path)
+                    .is_ok_and(|entries| entries.is_empty());
+                if empty {
+                    options.fs.remove_dir(&path)?;
+                    options.state.borrow_mut().folders.remove(&path);
+                    output::emit(Some(module), Event::Remove { path: &path });
+                }
+            }
+            Made::Link { path, target } => {
+                if options.fs.read_link(&path).is_ok_and(|link| link == target) {
+                    options.fs.remove_file(&path)?;
+                    output::emit(Some(module), Event::Remove { path: &path });
+                }
+            }
+            Made::File(path) => {
+                if options.fs.file_type(&path).is_ok() {
+                    options.fs.remove_file(&path)?;
+                    output::emit(Some(module), Event::Remove { path: &path });
+                }
+            }
+            Made::RemovedFolder { path, mode } => {
+                if options.fs.file_type(&path).is_err() {
+                    options.fs.create_dir(&path)?;
+                    options.fs.set_mode(&path, mode)?;
+                    options.state.borrow_mut().add_folder(&path);
+                    output::emit(Some(module), Event::Mkdir { path: &path });
+                }
+            }
+            Made::RemovedLink { path, target } => {
+                if options.fs.file_type(&path).is_err() {
+                    options.fs.symlink(&target, &path)?;
+                    output::emit(
+                        Some(module),
+                        Event::Link {
+                            path: &path,
+                            target: &target,
+                        },
+                    );
+                }
+            }
+            Made::RemovedFile {
+                path,
+                secret,
+                data,
+                mode,
+            } => {
+                if options.fs.file_type(&path).is_err() {
+                    options.fs.write(&path, &data, mode)?;
+                    output::emit(
+                        Some(module),
+                        Event::Decrypt {
+                            path: &path,
+                            target: &secret,
+                        },
+                    );
+                }
+            }
         }
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filesystem::memory::MemoryFilesystem;
+
+    fn options<'a>(fs: &'a MemoryFilesystem, state: &'a RefCell<State>) -> LinkOptions<'a> {
+        LinkOptions {
+            module: "bash",
+            secrets: None,
+            permissions: None,
+            ignore: None,
+            walk_errors: WalkErrors::Warn,
+            symlinks: Symlinks::Follow,
+            files: None,
+            dotfiles: false,
+            root: None,
+            fs,
+            state,
+        }
+    }
+
+    /// A `bash` module in `/dots/bash` linked into `/home/user`.
+    fn setup() -> Result<(MemoryFilesystem, PathBuf, PathBuf)> {
+        let fs = MemoryFilesystem::new();
+        fs.add_file("/dots/bash/.bashrc", b"bashrc")?;
+        fs.add_file("/dots/bash/.config/fish/config.fish", b"fish")?;
+        fs.add_dirs("/home/user")?;
+
+        Ok((fs, PathBuf::from("/home/user"), PathBuf::from("/dots/bash")))
+    }
+
+    fn states(options: &LinkOptions, from: &Path, to: &Path) -> Result<Vec<FileState>> {
+        Ok(file_states(options, from, to)?
+            .into_iter()
+            .map(|(_, _, state)| state)
+            .collect())
+    }
+
+    #[test]
+    fn links_and_unlinks() -> Result<()> {
+        let (fs, from, to) = setup()?;
+        let state = RefCell::default();
+        let options = options(&fs, &state);
+
+        assert_eq!(
+            get_link_status(&file_states(&options, &from, &to)?),
+            LinkStatus::Unlinked
+        );
+
+        link_folder(&options, &from, &to, true)?;
+
+        assert_eq!(
+            fs.read_link(Path::new("/home/user/.bashrc"))?,
+            Path::new("/dots/bash/.bashrc")
+        );
+        assert_eq!(
+            fs.read(Path::new("/home/user/.config/fish/config.fish"))?,
+            b"fish"
+        );
+        assert_eq!(
+            states(&options, &from, &to)?,
+            [FileState::Linked, FileState::Linked]
+        );
+
+        // Linking again changes nothing
+        link_folder(&options, &from, &to, true)?;
+
+        unlink_folder(&options, &from, &to, true)?;
+
+        assert!(!fs.exists(Path::new("/home/user/.bashrc")));
+        assert_eq!(
+            states(&options, &from, &to)?,
+            [FileState::Unlinked, FileState::Unlinked]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn plans_changes() -> Result<()> {
+        let (fs, from, to) = setup()?;
+        let state = RefCell::default();
+        let options = options(&fs, &state);
+
+        fs.add_dirs("/home/user/.config")?;
+        fs.add_file("/home/user/.bashrc", b"mine")?;
+
+        assert_eq!(
+            plan_folder(&options, &from, &to)?,
+            [
+                Change::Conflict {
+                    path: PathBuf::from("/home/user/.bashrc"),
+                    target: PathBuf::from("/dots/bash/.bashrc"),
+                },
+                Change::Mkdir {
+                    path: PathBuf::from("/home/user/.config/fish"),
+                },
+                Change::Link {
+                    path: PathBuf::from("/home/user/.config/fish/config.fish"),
+                    target: PathBuf::from("/dots/bash/.config/fish/config.fish"),
+                },
+            ]
+        );
+        // Nothing was changed
+        assert!(!fs.exists(Path::new("/home/user/.config/fish")));
+
+        Ok(())
+    }
+
+    #[test]
+    fn leaves_conflicts_alone() -> Result<()> {
+        let (fs, from, to) = setup()?;
+        let state = RefCell::default();
+        let options = options(&fs, &state);
+
+        fs.add_file("/home/user/.bashrc", b"mine")?;
+
+        assert!(link_folder(&options, &from, &to, true).is_err());
+        assert_eq!(
+            get_link_status(&file_states(&options, &from, &to)?),
+            LinkStatus::Conflict
+        );
+
+        // Fails once the rest is unlinked
+        let error = unlink_folder(&options, &from, &to, true).unwrap_err();
+        assert!(matches!(Error::find(&error), Some(Error::NotOwned(_))));
+        assert_eq!(fs.read(Path::new("/home/user/.bashrc"))?, b"mine");
+
+        Ok(())
+    }
+
+    #[test]
+    fn skips_symlink_loops_and_special_files() -> Result<()> {
+        let (fs, from, to) = setup()?;
+        let state = RefCell::default();
+        let options = options(&fs, &state);
+
+        fs.symlink(Path::new("/dots/bash/b"), Path::new("/dots/bash/a"))?;
+        fs.symlink(Path::new("/dots/bash/a"), Path::new("/dots/bash/b"))?;
+        fs.add_other("/dots/bash/socket")?;
+
+        link_folder(&options, &from, &to, true)?;
+
+        assert!(fs.file_type(Path::new("/home/user/a")).is_err());
+        assert!(fs.file_type(Path::new("/home/user/socket")).is_err());
+        assert_eq!(states(&options, &from, &to)?.len(), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn skips_unreadable_folders() -> Result<()> {
+        let (fs, from, to) = setup()?;
+        let state = RefCell::default();
+        let options = options(&fs, &state);
+
+        fs.set_mode(Path::new("/dots/bash/.config"), 0o300)?;
+
+        link_folder(&options, &from, &to, true)?;
+        assert_eq!(states(&options, &from, &to)?, [FileState::Linked]);
+
+        unlink_folder(&options, &from, &to, true)?;
+        assert!(!fs.exists(Path::new("/home/user/.bashrc")));
+
+        Ok(())
+    }
+
+    #[test]
+    fn fails_on_unlinkable_entries() -> Result<()> {
+        let (fs, from, to) = setup()?;
+        let state = RefCell::default();
+        let options = LinkOptions {
+            walk_errors: WalkErrors::Fail,
+            ..options(&fs, &state)
+        };
+
+        fs.add_other("/dots/bash/socket")?;
+        let error = link_folder(&options, &from, &to, true).unwrap_err();
+        assert!(format!("{:#}", error).contains("/dots/bash/socket"));
+        assert!(!fs.exists(Path::new("/home/user/.bashrc")));
+        fs.remove_file(Path::new("/dots/bash/socket"))?;
+
+        fs.symlink(Path::new("/dots/bash/loop"), Path::new("/dots/bash/loop"))?;
+        let error = file_states(&options, &from, &to).unwrap_err();
+        assert!(format!("{:#}", error).contains("/dots/bash/loop"));
+        fs.remove_file(Path::new("/dots/bash/loop"))?;
+
+        fs.set_mode(Path::new("/dots/bash/.config"), 0o300)?;
+        let error = unlink_folder(&options, &from, &to, true).unwrap_err();
+        assert!(matches!(Error::find(&error), Some(Error::Permission(_))));
+
+        Ok(())
+    }
+
+    /// Adds a symlinked file, a symlinked folder outside the module and a symlink to the module itself.
+    fn add_module_symlinks(fs: &MemoryFilesystem) -> Result<()> {
+        fs.symlink(Path::new(".bashrc"), Path::new("/dots/bash/.zshrc"))?;
+        fs.add_file("/shared/nvim/init.vim", b"vim")?;
+        fs.symlink(Path::new("/shared/nvim"), Path::new("/dots/bash/nvim"))?;
+        fs.symlink(Path::new("/dots"), Path::new("/dots/bash/.config/dots"))?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn follows_module_symlinks() -> Result<()> {
+        let (fs, from, to) = setup()?;
+        let state = RefCell::default();
+        let options = options(&fs, &state);
+        add_module_symlinks(&fs)?;
+
+        link_folder(&options, &from, &to, true)?;
+
+        assert_eq!(
+            fs.read_link(Path::new("/home/user/.zshrc"))?,
+            Path::new("/dots/bash/.bashrc")
+        );
+        assert_eq!(
+            fs.read_link(Path::new("/home/user/nvim/init.vim"))?,
+            Path::new("/shared/nvim/init.vim")
+        );
+        // The loop is skipped
+        assert!(!fs.exists(Path::new("/home/user/.config/dots")));
+        assert_eq!(
+            get_link_status(&file_states(&options, &from, &to)?),
+            LinkStatus::Linked
+        );
+
+        unlink_folder(&options, &from, &to, true)?;
+        assert!(!fs.exists(Path::new("/home/user/nvim/init.vim")));
+
+        Ok(())
+    }
+
+    #[test]
+    fn preserves_module_symlinks() -> Result<()> {
+        let (fs, from, to) = setup()?;
+        let state = RefCell::default();
+        let options = LinkOptions {
+            symlinks: Symlinks::Preserve,
+            ..options(&fs, &state)
+        };
+        add_module_symlinks(&fs)?;
+
+        link_folder(&options, &from, &to, true)?;
+
+        assert_eq!(
+            fs.read_link(Path::new("/home/user/.zshrc"))?,
+            Path::new("/dots/bash/.zshrc")
+        );
+        assert_eq!(
+            fs.read_link(Path::new("/home/user/nvim"))?,
+            Path::new("/dots/bash/nvim")
+        );
+        assert_eq!(
+            fs.read_link(Path::new("/home/user/.config/dots"))?,
+            Path::new("/dots/bash/.config/dots")
+        );
+        assert_eq!(
+            get_link_status(&file_states(&options, &from, &to)?),
+            LinkStatus::Linked
+        );
+
+        unlink_folder(&options, &from, &to, true)?;
+        assert!(fs.file_type(Path::new("/home/user/nvim")).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_module_symlinks() -> Result<()> {
+        let (fs, from, to) = setup()?;
+        let state = RefCell::default();
+        let options = LinkOptions {
+            symlinks: Symlinks::Reject,
+            ..options(&fs, &state)
+        };
+
+        fs.symlink(Path::new(".bashrc"), Path::new("/dots/bash/.zshrc"))?;
+
+        let error = link_folder(&options, &from, &to, true).unwrap_err();
+        assert!(format!("{:#}", error).contains("/dots/bash/.zshrc"));
+        assert!(!fs.exists(Path::new("/home/user/.bashrc")));
+        assert!(file_states(&options, &from, &to).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn creates_empty_folders() -> Result<()> {
+        let (fs, from, to) = setup()?;
+        let state = RefCell::default();
+        let options = options(&fs, &state);
+
+        fs.add_dirs("/dots/bash/.local/share/mpd/playlists")?;
+        fs.add_dirs("/dots/bash/.cache")?;
+        fs.add_dirs("/home/user/.cache")?;
+
+        assert_eq!(
+            plan_folder(&options, &from, &to)?[..2],
+            [
+                Change::Link {
+                    path: PathBuf::from("/home/user/.bashrc"),
+                    target: PathBuf::from("/dots/bash/.bashrc"),
+                },
+                Change::Mkdir {
+                    path: PathBuf::from("/home/user/.config"),
+                },
+            ]
+        );
+
+        link_folder(&options, &from, &to, true)?;
+
+        assert!(fs.exists(Path::new("/home/user/.local/share/mpd/playlists")));
+        assert!(state
+            .borrow()
+            .folders
+            .contains(Path::new("/home/user/.local/share/mpd/playlists")));
+        assert!(!state
+            .borrow()
+            .folders
+            .contains(Path::new("/home/user/.cache")));
+        assert_eq!(
+            get_link_status(&file_states(&options, &from, &to)?),
+            LinkStatus::Linked
+        );
+        assert!(plan_folder(&options, &from, &to)?.is_empty());
+
+        unlink_folder(&options, &from, &to, true)?;
+
+        // Only the empty folder kdot created is removed
+        assert!(!fs.exists(Path::new("/home/user/.local/share/mpd/playlists")));
+        assert!(fs.exists(Path::new("/home/user/.cache")));
+
+        link_folder(&options, &from, &to, true)?;
+        fs.add_file("/home/user/.local/share/mpd/playlists/mine.m3u", b"")?;
+        unlink_folder(&options, &from, &to, true)?;
+
+        assert!(fs.exists(Path::new("/home/user/.local/share/mpd/playlists/mine.m3u")));
+
+        Ok(())
+    }
+
+    #[test]
+    fn removes_created_folders() -> Result<()> {
+        let (fs, from, to) = setup()?;
+        let state = RefCell::default();
+        let options = options(&fs, &state);
+
+        link_folder(&options, &from, &to, true)?;
+        assert!(fs.exists(Path::new("/home/user/.config/fish")));
+
+        unlink_folder(&options, &from, &to, true)?;
+        assert!(!fs.exists(Path::new("/home/user/.config")));
+        assert!(fs.exists(Path::new("/home/user")));
+        assert!(state.borrow().folders.is_empty());
+
+        // Folders that were there before are kept, as are folders with other files
+        fs.add_dirs("/home/user/.config")?;
+        link_folder(&options, &from, &to, true)?;
+        fs.add_file("/home/user/.config/fish/fish_variables", b"")?;
+
+        unlink_folder(&options, &from, &to, true)?;
+        assert!(fs.exists(Path::new("/home/user/.config/fish/fish_variables")));
+
+        fs.remove_file(Path::new("/home/user/.config/fish/fish_variables"))?;
+        unlink_folder(&options, &from, &to, true)?;
+        assert!(!fs.exists(Path::new("/home/user/.config/fish")));
+        assert!(fs.exists(Path::new("/home/user/.config")));
+
+        Ok(())
+    }
+
+    #[test]
+    fn undoes_only_its_changes() -> Result<()> {
+        let (fs, from, to) = setup()?;
+        let state = RefCell::default();
+        let options = options(&fs, &state);
+
+        // Linked before the run
+        fs.symlink(
+            Path::new("/dots/bash/.bashrc"),
+            Path::new("/home/user/.bashrc"),
+        )?;
+
+        link_folder(&options, &from, &to, true)?;
+        undo(&options)?;
+        assert!(fs.read_link(Path::new("/home/user/.bashrc")).is_ok());
+        assert!(!fs.exists(Path::new("/home/user/.config")));
+        assert!(state.borrow().folders.is_empty());
+
+        // Unlinked before the run
+        fs.remove_file(Path::new("/home/user/.bashrc"))?;
+        link_folder(&options, &from, &to, true)?;
+        fs.remove_file(Path::new("/home/user/.bashrc"))?;
+        state.borrow_mut().made.clear();
+
+        unlink_folder(&options, &from, &to, true)?;
+        assert!(!fs.exists(Path::new("/home/user/.config")));
+        undo(&options)?;
+        assert!(fs
+            .read_link(Path::new("/home/user/.config/fish/config.fish"))
+            .is_ok());
+        assert!(!fs.exists(Path::new("/home/user/.bashrc")));
+        assert!(state.borrow().made.is_empty());
+
+        Ok(())
+    }
+
+    /// A xorshift generator, so the random trees are the same on every run.
+    struct Random(u64);
+
+    impl Random {
+        fn below(&mut self, n: u64) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0 % n
+        }
+    }
+
+    /// Adds random files and (possibly empty) folders inside `folder`, returning the folders relative to `root`.
+    fn add_random_tree(
+        fs: &MemoryFilesystem,
+        random: &mut Random,
+        root: &Path,
+        folder: &Path,
+    ) -> Result<Vec<PathBuf>> {
+        let mut folders = Vec::new();
+        let depth = folder.strip_prefix(root)?.components().count();
+
+        for name in &["a", "b", ".c", "d.txt"] {
+            let path = folder.join(name);
+            match random.below(3) {
+                0 => {}
+                1 => fs.add_file(&path, name.as_bytes())?,
+                _ if depth < 3 => {
+                    fs.add_dirs(&path)?;
+                    folders.push(path.strip_prefix(root)?.to_path_buf());
+                    folders.extend(add_random_tree(fs, random, root, &path)?);
+                }
+                _ => {}
+            }
+        }
+
+        Ok(folders)
+    }
+
+    /// Every entry inside `folder`, to compare the whole tree.
+    fn snapshot(fs: &MemoryFilesystem, folder: &Path) -> Vec<(PathBuf, Option<FileType>)> {
+        filesystem::walk(fs, folder, &|_, _| true)
+            .into_iter()
+            .map(|(path, file_type)| (path, file_type.ok()))
+            .collect()
+    }
+
+    #[test]
+    fn links_and_unlinks_random_trees() -> Result<()> {
+        let from = PathBuf::from("/home/user");
+        let to = PathBuf::from("/dots/module");
+
+        for seed in 1..=64 {
+            let mut random = Random(seed);
+            let fs = MemoryFilesystem::new();
+            fs.add_file(to.join("file"), b"")?;
+            let folders = add_random_tree(&fs, &mut random, &to, &to)?;
+
+            // Some of the folders already exist, with files of the user in them
+            fs.add_file(from.join("user"), b"")?;
+            for folder in &folders {
+                if random.below(2) == 0 {
+                    fs.add_file(from.join(folder).join("user"), b"")?;
+                }
+            }
+
+            let state = RefCell::default();
+            let options = options(&fs, &state);
+            let before = snapshot(&fs, &from);
+
+            let changes = plan_folder(&options, &from, &to)?;
+            assert!(
+                !changes
+                    .iter()
+                    .any(|change| matches!(change, Change::Conflict { .. })),
+                "seed {}",
+                seed
+            );
+
+            link_folder(&options, &from, &to, true)?;
+            assert_eq!(
+                get_link_status(&file_states(&options, &from, &to)?),
+                LinkStatus::Linked,
+                "seed {}",
+                seed
+            );
+            assert!(
+                plan_folder(&options, &from, &to)?.is_empty(),
+                "seed {}",
+                seed
+            );
+
+            // Linking again changes nothing
+            let linked = snapshot(&fs, &from);
+            link_folder(&options, &from, &to, true)?;
+            assert_eq!(snapshot(&fs, &from), linked, "seed {}", seed);
+
+            unlink_folder(&options, &from, &to, true)?;
+            assert_eq!(snapshot(&fs, &from), before, "seed {}", seed);
+            assert!(state.borrow().folders.is_empty(), "seed {}", seed);
+
+            // Undoing a link run restores the tree as well
+            state.borrow_mut().made.clear();
+            link_folder(&options, &from, &to, true)?;
+            undo(&options)?;
+            assert_eq!(snapshot(&fs, &from), before, "seed {}", seed);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn fails_without_permission() -> Result<()> {
+        let (fs, from, to) = setup()?;
+        let state = RefCell::default();
+        let options = options(&fs, &state);
+
+        fs.set_mode(Path::new("/home/user"), 0o555)?;
+
+        assert!(link_folder(&options, &from, &to, true).is_err());
+        assert!(!fs.exists(Path::new("/home/user/.bashrc")));
+
+        Ok(())
+    }
+}
@@ -1,7 +1,18 @@
+#![allow(
+    clippy::type_complexity,
+    clippy::bool_assert_comparison,
+    clippy::to_string_in_format_args
+)]
+
 use assert_cmd::prelude::*; // Add methods on commands
 use predicates::prelude::*;
 use serde_json::json; // Used for writing assertions
-use std::{fs, path::PathBuf, process::Command}; // Run programs
+use std::os::unix::fs::PermissionsExt;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+}; // Run programs
 use std::{fs::File, io::Write};
 use tempfile::TempDir;
 
@@ -368,3 +379,1836 @@ fn syncs_multiple_modules() -> Result<(), Box<dyn std::error::Error>> {
     //  TODO
     Ok(())
 }
+
+#[test]
+fn prints_config_schema() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp_dir = TempDir::new()?;
+
+    // Does not need a `kdot.json` file
+    let mut cmd = Command::cargo_bin("kdot")?;
+    cmd.current_dir(tmp_dir.path()).arg("schema");
+
+    let output = cmd.assert().success().get_output().stdout.clone();
+    let schema: serde_json::Value = serde_json::from_slice(&output)?;
+
+    assert_eq!(schema["title"], "PackageConfig");
+    assert!(schema["definitions"]["ModuleConfig"]["properties"]["location"].is_object());
+
+    // The shorthand location is documented
+    let shorthand = &schema["definitions"]["Location"]["anyOf"][1];
+    assert_eq!(shorthand["type"], "string");
+    assert!(shorthand["description"].is_string());
+
+    Ok(())
+}
+
+#[test]
+fn inits_empty_config() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp_dir = TempDir::new()?;
+
+    let mut cmd = Command::cargo_bin("kdot")?;
+    cmd.current_dir(tmp_dir.path()).arg("init").arg("dots");
+
+    cmd.assert().success();
+
+    let config: serde_json::Value =
+        serde_json::from_str(&fs::read_to_string(tmp_dir.path().join("dots/kdot.json"))?)?;
+    assert_eq!(config, json!({ "modules": [] }));
+
+    // Does not override an existing config
+    let mut cmd = Command::cargo_bin("kdot")?;
+    cmd.current_dir(tmp_dir.path()).arg("init").arg("dots");
+
+    cmd.assert().failure();
+
+    Ok(())
+}
+
+#[test]
+fn inits_and_adopts_scanned_folders() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp_dir = TempDir::new()?;
+
+    let config_path = tmp_dir.path().join("config");
+    fs::create_dir_all(config_path.join("nvim/lua"))?;
+    File::create(config_path.join("nvim/lua/init.lua"))?.write_all(b"this is the init.lua!")?;
+    fs::create_dir_all(config_path.join("fish"))?;
+    File::create(config_path.join("fish/config.fish"))?;
+    // Files are not modules
+    File::create(config_path.join("mimeapps.list"))?;
+
+    let mut cmd = Command::cargo_bin("kdot")?;
+    cmd.current_dir(tmp_dir.path())
+        .arg("init")
+        .arg("dots")
+        .arg("--scan")
+        .arg("config")
+        .arg("--adopt");
+
+    cmd.assert().success();
+
+    let dots_path = tmp_dir.path().join("dots");
+    let config: serde_json::Value =
+        serde_json::from_str(&fs::read_to_string(dots_path.join("kdot.json"))?)?;
+    let modules = config["modules"].as_array().unwrap();
+    assert_eq!(modules.len(), 2);
+    assert_eq!(modules[0]["name"], "fish");
+    assert_eq!(modules[1]["name"], "nvim");
+    assert_eq!(modules[1]["location"]["from"], "nvim");
+
+    let exists_and_symlink = predicate::path::exists().and(predicate::path::is_symlink());
+
+    // Moved into the repository and linked back
+    let init_lua = config_path.join("nvim/lua/init.lua");
+    assert!(exists_and_symlink.eval(&init_lua));
+    assert_eq!(
+        fs::canonicalize(&init_lua)?,
+        fs::canonicalize(dots_path.join("nvim/lua/init.lua"))?
+    );
+    assert!(exists_and_symlink.eval(&config_path.join("fish/config.fish")));
+    assert!(predicate::path::is_symlink()
+        .not()
+        .eval(&config_path.join("mimeapps.list")));
+
+    Ok(())
+}
+
+#[test]
+fn refuses_to_adopt_ignored_files() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp_dir = TempDir::new()?;
+
+    let config_path = tmp_dir.path().join("config");
+    fs::create_dir_all(config_path.join("fish"))?;
+    File::create(config_path.join("fish/config.fish"))?;
+    fs::create_dir_all(config_path.join("nvim/.git"))?;
+    File::create(config_path.join("nvim/.git/HEAD"))?;
+    File::create(config_path.join("nvim/README.md"))?;
+    File::create(config_path.join("nvim/init.lua"))?;
+
+    let mut cmd = Command::cargo_bin("kdot")?;
+    cmd.current_dir(tmp_dir.path())
+        .arg("init")
+        .arg("dots")
+        .arg("--scan")
+        .arg("config")
+        .arg("--adopt");
+
+    // They would vanish from the scanned folder, as the module never links them back
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains("nvim/.git"))
+        .stderr(predicate::str::contains("nvim/README.md"))
+        .stderr(predicate::str::contains("init.lua").not());
+
+    for file in [
+        "fish/config.fish",
+        "nvim/.git/HEAD",
+        "nvim/README.md",
+        "nvim/init.lua",
+    ] {
+        assert!(predicate::path::is_symlink()
+            .not()
+            .eval(&config_path.join(file)));
+        assert!(config_path.join(file).is_file());
+    }
+    assert!(predicate::path::exists()
+        .not()
+        .eval(&tmp_dir.path().join("dots/kdot.json")));
+
+    Ok(())
+}
+
+#[test]
+fn restores_folders_when_adopting_fails() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp_dir = TempDir::new()?;
+
+    let config_path = tmp_dir.path().join("config");
+    fs::create_dir_all(config_path.join("fish"))?;
+    fs::write(config_path.join("fish/config.fish"), "fish")?;
+    fs::create_dir_all(config_path.join("nvim"))?;
+    fs::write(config_path.join("nvim/init.lua"), "nvim")?;
+    let dots_path = tmp_dir.path().join("dots");
+
+    let adopt = || -> Result<Command, Box<dyn std::error::Error>> {
+        let mut cmd = Command::cargo_bin("kdot")?;
+        cmd.current_dir(tmp_dir.path())
+            .arg("init")
+            .arg("dots")
+            .arg("--scan")
+            .arg("config")
+            .arg("--adopt");
+        Ok(cmd)
+    };
+
+    // Every folder is checked before the first one is moved
+    fs::create_dir_all(dots_path.join("nvim"))?;
+    adopt()?.assert().code(4);
+    assert_eq!(
+        fs::read_to_string(config_path.join("fish/config.fish"))?,
+        "fish"
+    );
+    fs::remove_dir_all(&dots_path)?;
+
+    // A secret cannot be linked without "secrets", after "fish" was adopted and linked
+    File::create(config_path.join("nvim/token.age"))?;
+    adopt()?.assert().code(2);
+
+    for file in ["fish/config.fish", "nvim/init.lua", "nvim/token.age"] {
+        assert!(predicate::path::is_symlink()
+            .not()
+            .eval(&config_path.join(file)));
+        assert!(config_path.join(file).is_file());
+    }
+    assert_eq!(
+        fs::read_to_string(config_path.join("fish/config.fish"))?,
+        "fish"
+    );
+    assert!(predicate::path::exists()
+        .not()
+        .eval(&dots_path.join("kdot.json")));
+    assert!(predicate::path::exists()
+        .not()
+        .eval(&dots_path.join("fish")));
+
+    Ok(())
+}
+
+#[test]
+fn lists_modules_as_json() -> Result<(), Box<dyn std::error::Error>> {
+    let (tmp_dir, (from_path, from_path_string), (to_path, to_path_string)) = setup_config()?;
+
+    File::create(from_path.join("bashrc"))?;
+    File::create(from_path.join("bash_profile"))?;
+    std::os::unix::fs::symlink(from_path.join("bashrc"), to_path.join("bashrc"))?;
+
+    let list = || -> Result<serde_json::Value, Box<dyn std::error::Error>> {
+        let mut cmd = Command::cargo_bin("kdot")?;
+        cmd.current_dir(tmp_dir.path())
+            .arg("list")
+            .arg("--format")
+            .arg("json");
+
+        let output = cmd.assert().success().get_output().stdout.clone();
+        Ok(serde_json::from_slice(&output)?)
+    };
+
+    assert_eq!(
+        list()?,
+        json!([{
+            "name": "bash",
+            "from": from_path_string,
+            "to": to_path_string,
+            "locations": [{ "from": from_path_string, "to": to_path_string }],
+            "deps": [],
+            "tags": [],
+            "status": "partial"
+        }])
+    );
+
+    let mut cmd = Command::cargo_bin("kdot")?;
+    cmd.current_dir(tmp_dir.path()).arg("sync").arg("bash");
+    cmd.assert().success();
+
+    assert_eq!(list()?[0]["status"], "linked");
+
+    // Something else is in the way
+    fs::remove_file(to_path.join("bash_profile"))?;
+    File::create(to_path.join("bash_profile"))?;
+
+    assert_eq!(list()?[0]["status"], "conflict");
+
+    Ok(())
+}
+
+#[test]
+fn lists_modules() -> Result<(), Box<dyn std::error::Error>> {
+    let (tmp_dir, (from_path, _from_path_string), (_to_path, _to_path_string)) = setup_config()?;
+
+    File::create(from_path.join("bashrc"))?;
+
+    let mut cmd = Command::cargo_bin("kdot")?;
+    cmd.current_dir(tmp_dir.path()).arg("list");
+
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::starts_with("bash (unlinked)\n"));
+
+    Ok(())
+}
+
+/// Parses every line of stdout as a JSON event.
+fn json_events(stdout: &[u8]) -> Result<Vec<serde_json::Value>, Box<dyn std::error::Error>> {
+    let mut events = Vec::new();
+    for line in std::str::from_utf8(stdout)?.lines() {
+        events.push(serde_json::from_str(line)?);
+    }
+    Ok(events)
+}
+
+#[test]
+fn links_module_with_json_events() -> Result<(), Box<dyn std::error::Error>> {
+    let (tmp_dir, (from_path, from_path_string), (to_path, to_path_string)) = setup_config()?;
+
+    fs::create_dir_all(from_path.join("nested"))?;
+    File::create(from_path.join("nested/bashrc"))?;
+
+    let mut cmd = Command::cargo_bin("kdot")?;
+    cmd.current_dir(tmp_dir.path())
+        .arg("--format")
+        .arg("json")
+        .arg("link")
+        .arg("bash");
+
+    let output = cmd.assert().success().get_output().stdout.clone();
+    let mut events = json_events(&output)?;
+    let summary = events.pop().unwrap();
+
+    assert_eq!(
+        events,
+        vec![
+            json!({ "module": "bash", "event": "mkdir", "path": format!("{}/nested", to_path_string) }),
+            json!({
+                "module": "bash",
+                "event": "link",
+                "path": format!("{}/nested/bashrc", to_path_string),
+                "target": format!("{}/nested/bashrc", from_path_string)
+            }),
+        ]
+    );
+    assert_eq!(summary["event"], "summary");
+    assert_eq!(summary["success"], true);
+    assert_eq!(summary["mkdir"], 1);
+    assert_eq!(summary["link"], 1);
+    assert_eq!(summary["error"], 0);
+
+    // Something else is in the way
+    fs::remove_file(to_path.join("nested/bashrc"))?;
+    File::create(to_path.join("nested/bashrc"))?;
+
+    let mut cmd = Command::cargo_bin("kdot")?;
+    cmd.current_dir(tmp_dir.path())
+        .arg("link")
+        .arg("bash")
+        .arg("--format")
+        .arg("json");
+
+    let output = cmd.assert().failure().get_output().stdout.clone();
+    let events = json_events(&output)?;
+
+    assert_eq!(events[0]["event"], "conflict");
+    assert_eq!(events[1]["event"], "error");
+    assert_eq!(events[1]["module"], "bash");
+    assert_eq!(events[2]["event"], "summary");
+    assert_eq!(events[2]["success"], false);
+
+    Ok(())
+}
+
+#[test]
+fn reports_status_with_json_events() -> Result<(), Box<dyn std::error::Error>> {
+    let (tmp_dir, (from_path, _from_path_string), (to_path, to_path_string)) = setup_config()?;
+
+    File::create(from_path.join("bashrc"))?;
+    File::create(from_path.join("inputrc"))?;
+    std::os::unix::fs::symlink(from_path.join("bashrc"), to_path.join("bashrc"))?;
+
+    let mut cmd = Command::cargo_bin("kdot")?;
+    cmd.current_dir(tmp_dir.path())
+        .arg("status")
+        .arg("--format")
+        .arg("json");
+
+    let output = cmd.assert().success().get_output().stdout.clone();
+    let events = json_events(&output)?;
+
+    assert_eq!(events.len(), 3);
+    assert_eq!(events[0]["event"], "status");
+    assert_eq!(events[0]["path"], format!("{}/bashrc", to_path_string));
+    assert_eq!(events[0]["state"], "linked");
+    assert_eq!(events[1]["path"], format!("{}/inputrc", to_path_string));
+    assert_eq!(events[1]["state"], "unlinked");
+    assert_eq!(events[2]["status"], 2);
+
+    Ok(())
+}
+
+#[test]
+fn writes_log_file() -> Result<(), Box<dyn std::error::Error>> {
+    let (tmp_dir, (from_path, _from_path_string), (to_path, _to_path_string)) = setup_config()?;
+
+    File::create(from_path.join("bashrc"))?;
+    let log_path = tmp_dir.path().join("kdot.log");
+
+    let mut cmd = Command::cargo_bin("kdot")?;
+    cmd.current_dir(tmp_dir.path())
+        .arg("link")
+        .arg("bash")
+        .arg("--quiet")
+        .arg("--log-file")
+        .arg(&log_path);
+
+    cmd.assert().success().stdout(predicate::str::is_empty());
+
+    let mut cmd = Command::cargo_bin("kdot")?;
+    cmd.current_dir(tmp_dir.path())
+        .arg("unlink")
+        .arg("bash")
+        .arg("--log-file")
+        .arg(&log_path);
+
+    cmd.assert().success();
+
+    // Appended by both commands
+    let log = fs::read_to_string(&log_path)?;
+    let linked_file = to_path.join("bashrc");
+    assert!(log.contains(&format!("Linking \"{}\"", linked_file.display())));
+    assert!(log.contains(&format!("Unlinking \"{}\"", linked_file.display())));
+
+    Ok(())
+}
+
+#[test]
+fn reads_log_level_from_env() -> Result<(), Box<dyn std::error::Error>> {
+    let (tmp_dir, (from_path, _from_path_string), (_to_path, _to_path_string)) = setup_config()?;
+
+    File::create(from_path.join("bashrc"))?;
+
+    let mut cmd = Command::cargo_bin("kdot")?;
+    cmd.current_dir(tmp_dir.path())
+        .env("KDOT_LOG", "info")
+        .arg("link")
+        .arg("bash");
+
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("Linked \"bash\" module."));
+
+    let mut cmd = Command::cargo_bin("kdot")?;
+    cmd.current_dir(tmp_dir.path())
+        .env("KDOT_LOG", "loud")
+        .arg("status");
+
+    cmd.assert().failure();
+
+    Ok(())
+}
+
+/// Writes a `kdot.json` with a single `bash` module with the `hooks`.
+fn write_config_with_hooks(
+    tmp_dir: &TempDir,
+    from_path_string: &str,
+    to_path_string: &str,
+    hooks: serde_json::Value,
+) -> Result<(), Box<dyn std::error::Error>> {
+    fs::write(
+        tmp_dir.path().join("kdot.json"),
+        json!({
+          "modules": [
+            {
+              "name": "bash",
+              "location": {
+                "from": from_path_string,
+                "to": to_path_string
+              },
+              "hooks": hooks
+            }
+          ]
+        })
+        .to_string(),
+    )?;
+
+    Ok(())
+}
+
+#[test]
+fn runs_module_hooks() -> Result<(), Box<dyn std::error::Error>> {
+    let (tmp_dir, (from_path, from_path_string), (to_path, to_path_string)) = setup_config()?;
+
+    File::create(from_path.join("bashrc"))?;
+    // A script inside the module
+    fs::write(
+        from_path.join("post-link.sh"),
+        "echo \"$KDOT_HOOK $KDOT_MODULE $KDOT_TARGET_DIR\" >> ../hooks.log",
+    )?;
+
+    write_config_with_hooks(
+        &tmp_dir,
+        &from_path_string,
+        &to_path_string,
+        json!({
+            "pre_link": "test ! -e \"$KDOT_TARGET_DIR/bashrc\" && pwd >> ../hooks.log",
+            "post_link": ["sh post-link.sh"],
+            "post_unlink": "echo \"$KDOT_HOOK $KDOT_MODULE_DIR\" >> ../hooks.log"
+        }),
+    )?;
+
+    let mut cmd = Command::cargo_bin("kdot")?;
+    cmd.current_dir(tmp_dir.path()).arg("link").arg("bash");
+    cmd.assert().success();
+
+    let mut cmd = Command::cargo_bin("kdot")?;
+    cmd.current_dir(tmp_dir.path()).arg("unlink").arg("bash");
+    cmd.assert().success();
+
+    assert_eq!(
+        fs::read_to_string(tmp_dir.path().join("hooks.log"))?,
+        format!(
+            "{}\npost_link bash {}\npost_unlink {}\n",
+            fs::canonicalize(&from_path)?.display(),
+            to_path_string,
+            from_path_string
+        )
+    );
+    assert!(predicate::path::exists()
+        .not()
+        .eval(&to_path.join("bashrc")));
+
+    Ok(())
+}
+
+#[test]
+fn rolls_back_failed_hooks() -> Result<(), Box<dyn std::error::Error>> {
+    let (tmp_dir, (from_path, from_path_string), (to_path, to_path_string)) = setup_config()?;
+
+    File::create(from_path.join("bashrc"))?;
+
+    write_config_with_hooks(
+        &tmp_dir,
+        &from_path_string,
+        &to_path_string,
+        json!({ "post_link": "exit 3" }),
+    )?;
+
+    let mut cmd = Command::cargo_bin("kdot")?;
+    cmd.current_dir(tmp_dir.path()).arg("link").arg("bash");
+    cmd.assert()
+        .code(7)
+        .stderr(predicate::str::contains("post_link hook \"exit 3\""));
+
+    // Not left half linked
+    assert!(predicate::path::exists()
+        .not()
+        .eval(&to_path.join("bashrc")));
+
+    // Links that were there before the run are left alone
+    File::create(from_path.join("inputrc"))?;
+    std::os::unix::fs::symlink(from_path.join("inputrc"), to_path.join("inputrc"))?;
+
+    let mut cmd = Command::cargo_bin("kdot")?;
+    cmd.current_dir(tmp_dir.path()).arg("link").arg("bash");
+    cmd.assert().failure();
+
+    let is_symlink = predicate::path::is_symlink();
+    assert!(is_symlink.eval(&to_path.join("inputrc")));
+    assert!(predicate::path::exists()
+        .not()
+        .eval(&to_path.join("bashrc")));
+
+    // Only what the run unlinked is linked back
+    write_config_with_hooks(
+        &tmp_dir,
+        &from_path_string,
+        &to_path_string,
+        json!({ "post_unlink": "exit 3" }),
+    )?;
+
+    let mut cmd = Command::cargo_bin("kdot")?;
+    cmd.current_dir(tmp_dir.path()).arg("unlink").arg("bash");
+    cmd.assert().failure();
+
+    assert!(is_symlink.eval(&to_path.join("inputrc")));
+    assert!(predicate::path::exists()
+        .not()
+        .eval(&to_path.join("bashrc")));
+    fs::remove_file(to_path.join("inputrc"))?;
+
+    write_config_with_hooks(
+        &tmp_dir,
+        &from_path_string,
+        &to_path_string,
+        json!({ "pre_link": "false" }),
+    )?;
+
+    let mut cmd = Command::cargo_bin("kdot")?;
+    cmd.current_dir(tmp_dir.path()).arg("link").arg("bash");
+    cmd.assert().failure();
+
+    assert!(predicate::path::exists()
+        .not()
+        .eval(&to_path.join("bashrc")));
+
+    Ok(())
+}
+
+/// Writes an executable shell script standing in for a system command.
+fn write_stub(path: &Path, script: &str) -> Result<(), Box<dyn std::error::Error>> {
+    fs::write(path, format!("#!/bin/sh\n{}\n", script))?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o755))?;
+
+    Ok(())
+}
+
+#[test]
+fn installs_missing_packages() -> Result<(), Box<dyn std::error::Error>> {
+    let (tmp_dir, (_from_path, from_path_string), (_to_path, to_path_string)) = setup_config()?;
+
+    let pacman = tmp_dir.path().join("pacman");
+    let log = tmp_dir.path().join("pacman.log");
+    write_stub(
+        &pacman,
+        &format!(
+            "if [ \"$1\" = -Qq ]; then printf 'bash\\ngit\\n'; else echo \"$0 $*\" >> {}; fi",
+            log.display()
+        ),
+    )?;
+    let paru = tmp_dir.path().join("paru");
+    write_stub(&paru, &format!("echo \"$0 $*\" >> {}", log.display()))?;
+
+    fs::write(
+        tmp_dir.path().join("kdot.json"),
+        json!({
+          "package_manager": {
+            "install": pacman,
+            "query": pacman,
+            "aur": paru
+          },
+          "modules": [
+            {
+              "name": "bash",
+              "location": { "from": from_path_string, "to": to_path_string },
+              "packages": ["bash", "bash-completion", "fzf"],
+              "aur": ["git", "starship-bin"]
+            }
+          ]
+        })
+        .to_string(),
+    )?;
+
+    let mut cmd = Command::cargo_bin("kdot")?;
+    cmd.current_dir(tmp_dir.path()).arg("install").arg("bash");
+
+    cmd.assert().success();
+
+    assert_eq!(
+        fs::read_to_string(&log)?,
+        format!(
+            "{} -S --needed bash-completion fzf\n{} -S --needed starship-bin\n",
+            pacman.display(),
+            paru.display()
+        )
+    );
+
+    Ok(())
+}
+
+#[test]
+fn diffs_declared_and_installed_packages() -> Result<(), Box<dyn std::error::Error>> {
+    let (tmp_dir, (_from_path, from_path_string), (_to_path, to_path_string)) = setup_config()?;
+
+    let pacman = tmp_dir.path().join("pacman");
+    write_stub(
+        &pacman,
+        "case \"$1\" in\n-Qqe) printf 'bash\\nfirefox\\nvim\\n' ;;\n-Qq) printf 'bash\\nfirefox\\nncurses\\nvim\\n' ;;\nesac",
+    )?;
+
+    let config_path = tmp_dir.path().join("kdot.json");
+    fs::write(
+        &config_path,
+        json!({
+          "package_manager": { "query": pacman },
+          "modules": [
+            {
+              "name": "bash",
+              "location": { "from": from_path_string, "to": to_path_string },
+              // Installed as a dependency only
+              "packages": ["bash", "fzf", "ncurses"]
+            },
+            {
+              "name": "desktop",
+              "location": { "from": from_path_string, "to": to_path_string }
+            }
+          ]
+        })
+        .to_string(),
+    )?;
+
+    let mut cmd = Command::cargo_bin("kdot")?;
+    cmd.current_dir(tmp_dir.path())
+        .arg("--format")
+        .arg("json")
+        .arg("packages")
+        .arg("diff")
+        .arg("--add-to")
+        .arg("desktop");
+
+    let output = cmd.assert().success().get_output().stdout.clone();
+    let diff: serde_json::Value = serde_json::from_slice(&output)?;
+
+    assert_eq!(
+        diff,
+        json!({ "missing": ["fzf"], "undeclared": ["firefox", "vim"] })
+    );
+
+    let config: serde_json::Value = serde_json::from_str(&fs::read_to_string(&config_path)?)?;
+    assert_eq!(
+        config["modules"][0]["packages"],
+        json!(["bash", "fzf", "ncurses"])
+    );
+    assert_eq!(config["modules"][1]["packages"], json!(["firefox", "vim"]));
+    // The rest is kept
+    assert_eq!(config["package_manager"]["query"], json!(pacman));
+
+    Ok(())
+}
+
+#[test]
+fn manages_module_services() -> Result<(), Box<dyn std::error::Error>> {
+    let (tmp_dir, (from_path, from_path_string), (to_path, to_path_string)) = setup_config()?;
+
+    fs::create_dir_all(from_path.join("systemd/user"))?;
+    File::create(from_path.join("systemd/user/mpd.service"))?;
+
+    let systemctl = tmp_dir.path().join("systemctl");
+    let log = tmp_dir.path().join("systemctl.log");
+    write_stub(&systemctl, &format!("echo \"$*\" >> {}", log.display()))?;
+
+    fs::write(
+        tmp_dir.path().join("kdot.json"),
+        json!({
+          "systemctl": systemctl,
+          "modules": [
+            {
+              "name": "bash",
+              "location": { "from": from_path_string, "to": to_path_string },
+              "services": ["mpd.service"]
+            }
+          ]
+        })
+        .to_string(),
+    )?;
+
+    let mut cmd = Command::cargo_bin("kdot")?;
+    cmd.current_dir(tmp_dir.path()).arg("link").arg("bash");
+    cmd.assert().success();
+
+    assert!(predicate::path::is_symlink().eval(&to_path.join("systemd/user/mpd.service")));
+    assert_eq!(
+        fs::read_to_string(&log)?,
+        "--user daemon-reload\n--user enable --now mpd.service\n"
+    );
+
+    let mut cmd = Command::cargo_bin("kdot")?;
+    cmd.current_dir(tmp_dir.path()).arg("unlink").arg("bash");
+    cmd.assert().success();
+
+    assert!(fs::read_to_string(&log)?.ends_with("--user disable --now mpd.service\n"));
+
+    // A failing post_link hook disables the services it enabled again
+    fs::remove_file(&log)?;
+    fs::write(
+        tmp_dir.path().join("kdot.json"),
+        json!({
+          "systemctl": systemctl,
+          "modules": [
+            {
+              "name": "bash",
+              "location": { "from": from_path_string, "to": to_path_string },
+              "services": ["mpd.service"],
+              "hooks": { "post_link": "exit 3" }
+            }
+          ]
+        })
+        .to_string(),
+    )?;
+
+    let mut cmd = Command::cargo_bin("kdot")?;
+    cmd.current_dir(tmp_dir.path()).arg("link").arg("bash");
+    cmd.assert().failure();
+
+    assert_eq!(
+        fs::read_to_string(&log)?,
+        "--user daemon-reload\n--user enable --now mpd.service\n--user disable --now mpd.service\n"
+    );
+    assert!(predicate::path::exists()
+        .not()
+        .eval(&to_path.join("systemd/user/mpd.service")));
+
+    // Failing to enable a service rolls back the link
+    write_stub(&systemctl, "exit 1")?;
+
+    let mut cmd = Command::cargo_bin("kdot")?;
+    cmd.current_dir(tmp_dir.path()).arg("link").arg("bash");
+    cmd.assert().failure();
+
+    assert!(predicate::path::exists()
+        .not()
+        .eval(&to_path.join("systemd/user/mpd.service")));
+
+    Ok(())
+}
+
+/// Writes a `kdot.json` with a single `bash` module and secrets "encrypted" with base64 by a fake age.
+fn setup_secrets(
+    tmp_dir: &TempDir,
+    from_path_string: &str,
+    to_path_string: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let identity = tmp_dir.path().join("identity.txt");
+    fs::write(&identity, "AGE-SECRET-KEY-1")?;
+
+    let age = tmp_dir.path().join("age");
+    write_stub(
+        &age,
+        "test -f \"$3\" || exit 1
+case \"$1\" in
+  --decrypt) base64 -d \"$4\" ;;
+  --encrypt) base64 \"$6\" > \"$5\" ;;
+esac",
+    )?;
+
+    fs::write(
+        tmp_dir.path().join("kdot.json"),
+        json!({
+          "secrets": { "identity": identity, "age": age },
+          "modules": [
+            {
+              "name": "bash",
+              "location": { "from": from_path_string, "to": to_path_string }
+            }
+          ]
+        })
+        .to_string(),
+    )?;
+
+    Ok(())
+}
+
+#[test]
+fn decrypts_module_secrets() -> Result<(), Box<dyn std::error::Error>> {
+    let (tmp_dir, (from_path, from_path_string), (to_path, to_path_string)) = setup_config()?;
+    setup_secrets(&tmp_dir, &from_path_string, &to_path_string)?;
+
+    File::create(from_path.join("bashrc"))?;
+    let netrc = to_path.join(".netrc");
+    fs::write(&netrc, "machine example.com password hunter2\n")?;
+
+    let mut cmd = Command::cargo_bin("kdot")?;
+    cmd.current_dir(tmp_dir.path())
+        .arg("secret")
+        .arg("add")
+        .arg("bash")
+        .arg(&netrc);
+    cmd.assert().success();
+
+    let secret = from_path.join(".netrc.age");
+    assert!(secret.is_file());
+    assert_ne!(fs::read(&secret)?, fs::read(&netrc)?);
+
+    fs::remove_file(&netrc)?;
+
+    let mut cmd = Command::cargo_bin("kdot")?;
+    cmd.current_dir(tmp_dir.path()).arg("link").arg("bash");
+    cmd.assert().success();
+
+    // Decrypted, never linked
+    let metadata = fs::symlink_metadata(&netrc)?;
+    assert!(metadata.is_file());
+    assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+    assert_eq!(
+        fs::read_to_string(&netrc)?,
+        "machine example.com password hunter2\n"
+    );
+    assert!(predicate::path::is_symlink().eval(&to_path.join("bashrc")));
+    assert!(predicate::path::exists()
+        .not()
+        .eval(&to_path.join(".netrc.age")));
+
+    let mut cmd = Command::cargo_bin("kdot")?;
+    cmd.current_dir(tmp_dir.path()).arg("unlink").arg("bash");
+    cmd.assert().success();
+
+    assert!(predicate::path::exists().not().eval(&netrc));
+
+    Ok(())
+}
+
+#[test]
+fn edits_module_secrets() -> Result<(), Box<dyn std::error::Error>> {
+    let (tmp_dir, (from_path, from_path_string), (to_path, to_path_string)) = setup_config()?;
+    setup_secrets(&tmp_dir, &from_path_string, &to_path_string)?;
+
+    fs::write(from_path.join("token.age"), "b2xkCg==\n")?;
+
+    let mut cmd = Command::cargo_bin("kdot")?;
+    cmd.current_dir(tmp_dir.path()).arg("link").arg("bash");
+    cmd.assert().success();
+    assert_eq!(fs::read_to_string(to_path.join("token"))?, "old\n");
+
+    let editor = tmp_dir.path().join("editor");
+    write_stub(&editor, "echo new >> \"$1\"")?;
+
+    let mut cmd = Command::cargo_bin("kdot")?;
+    cmd.current_dir(tmp_dir.path())
+        .env("EDITOR", &editor)
+        .env_remove("VISUAL")
+        .arg("secret")
+        .arg("edit")
+        .arg("bash")
+        .arg("token");
+    cmd.assert().success();
+
+    assert_eq!(
+        fs::read_to_string(from_path.join("token.age"))?,
+        "b2xkCm5ldwo=\n"
+    );
+
+    // The decrypted copy follows the edit, so syncing does not see it as changed
+    assert_eq!(fs::read_to_string(to_path.join("token"))?, "old\nnew\n");
+
+    let mut cmd = Command::cargo_bin("kdot")?;
+    cmd.current_dir(tmp_dir.path()).arg("sync").arg("bash");
+    cmd.assert().success();
+    assert_eq!(fs::read_to_string(to_path.join("token"))?, "old\nnew\n");
+
+    Ok(())
+}
+
+#[test]
+fn applies_module_permissions() -> Result<(), Box<dyn std::error::Error>> {
+    let (tmp_dir, (from_path, from_path_string), (to_path, to_path_string)) = setup_config()?;
+
+    fs::create_dir_all(from_path.join(".ssh"))?;
+    File::create(from_path.join(".ssh/config"))?;
+    fs::create_dir_all(from_path.join(".gnupg/private"))?;
+    File::create(from_path.join(".gnupg/private/key"))?;
+
+    fs::write(
+        tmp_dir.path().join("kdot.json"),
+        json!({
+          "modules": [
+            {
+              "name": "bash",
+              "location": { "from": from_path_string, "to": to_path_string },
+              "permissions": { ".ssh": "700", ".gnupg/**": "0750", ".gnupg": "700" }
+            }
+          ]
+        })
+        .to_string(),
+    )?;
+
+    let mut cmd = Command::cargo_bin("kdot")?;
+    cmd.current_dir(tmp_dir.path()).arg("link").arg("bash");
+    cmd.assert().success();
+
+    let mode = |path: &str| -> Result<u32, Box<dyn std::error::Error>> {
+        Ok(fs::metadata(to_path.join(path))?.permissions().mode() & 0o7777)
+    };
+
+    assert_eq!(mode(".ssh")?, 0o700);
+    assert_eq!(mode(".gnupg")?, 0o700);
+    assert_eq!(mode(".gnupg/private")?, 0o750);
+
+    fs::set_permissions(to_path.join(".ssh"), fs::Permissions::from_mode(0o755))?;
+
+    let mut cmd = Command::cargo_bin("kdot")?;
+    cmd.current_dir(tmp_dir.path())
+        .arg("status")
+        .arg("--format")
+        .arg("json");
+
+    let output = cmd.assert().success().get_output().stdout.clone();
+    let events = json_events(&output)?;
+    let modes: Vec<_> = events
+        .iter()
+        .filter(|event| event["event"] == "mode")
+        .collect();
+
+    assert_eq!(
+        modes,
+        vec![&json!({
+            "module": "bash",
+            "event": "mode",
+            "path": format!("{}/.ssh", to_path_string),
+            "mode": "755",
+            "expected": "700"
+        })]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn ignores_module_files() -> Result<(), Box<dyn std::error::Error>> {
+    let (tmp_dir, (from_path, from_path_string), (to_path, to_path_string)) = setup_config()?;
+
+    fs::create_dir_all(from_path.join(".git"))?;
+    File::create(from_path.join(".git/HEAD"))?;
+    File::create(from_path.join("README.md"))?;
+    File::create(from_path.join(".bashrc"))?;
+    File::create(from_path.join(".bashrc.swp"))?;
+    File::create(from_path.join("install.sh"))?;
+    fs::create_dir_all(from_path.join("docs"))?;
+    File::create(from_path.join("docs/notes.md"))?;
+    File::create(from_path.join("docs/keep.md"))?;
+    fs::write(from_path.join(".kdotignore"), "install.sh\n")?;
+
+    fs::write(
+        tmp_dir.path().join("kdot.json"),
+        json!({
+          "modules": [
+            {
+              "name": "bash",
+              "location": { "from": from_path_string, "to": to_path_string },
+              "ignore": ["*.md", "!keep.md"]
+            }
+          ]
+        })
+        .to_string(),
+    )?;
+
+    let mut cmd = Command::cargo_bin("kdot")?;
+    cmd.current_dir(tmp_dir.path()).arg("link").arg("bash");
+    cmd.assert().success();
+
+    let does_not_exist = predicate::path::exists().not();
+
+    assert!(predicate::path::is_symlink().eval(&to_path.join(".bashrc")));
+    assert!(predicate::path::is_symlink().eval(&to_path.join("docs/keep.md")));
+    for ignored in &[
+        ".git",
+        "README.md",
+        ".bashrc.swp",
+        "install.sh",
+        ".kdotignore",
+        "docs/notes.md",
+    ] {
+        assert!(does_not_exist.eval(&to_path.join(ignored)), "{}", ignored);
+    }
+
+    // Ignored files are not reported either
+    let mut cmd = Command::cargo_bin("kdot")?;
+    cmd.current_dir(tmp_dir.path())
+        .arg("status")
+        .arg("--format")
+        .arg("json");
+
+    let output = cmd.assert().success().get_output().stdout.clone();
+    let events = json_events(&output)?;
+    assert_eq!(events.last().unwrap()["status"], 2);
+
+    // Ignored files in the way are left alone
+    File::create(to_path.join("install.sh"))?;
+
+    let mut cmd = Command::cargo_bin("kdot")?;
+    cmd.current_dir(tmp_dir.path()).arg("unlink").arg("bash");
+    cmd.assert().success();
+
+    assert!(does_not_exist.eval(&to_path.join(".bashrc")));
+    assert!(to_path.join("install.sh").is_file());
+
+    Ok(())
+}
+
+#[test]
+fn translates_dot_prefixes() -> Result<(), Box<dyn std::error::Error>> {
+    let (tmp_dir, (from_path, from_path_string), (to_path, to_path_string)) = setup_config()?;
+
+    File::create(from_path.join("dot-bashrc"))?;
+    fs::create_dir_all(from_path.join("dot-config/fish"))?;
+    File::create(from_path.join("dot-config/fish/config.fish"))?;
+    File::create(from_path.join("not-dot-file"))?;
+
+    fs::write(
+        tmp_dir.path().join("kdot.json"),
+        json!({
+          "modules": [
+            {
+              "name": "bash",
+              "location": { "from": from_path_string, "to": to_path_string },
+              "dotfiles": true
+            }
+          ]
+        })
+        .to_string(),
+    )?;
+
+    let mut cmd = Command::cargo_bin("kdot")?;
+    cmd.current_dir(tmp_dir.path()).arg("link").arg("bash");
+    cmd.assert().success();
+
+    let is_symlink = predicate::path::is_symlink();
+    assert!(is_symlink.eval(&to_path.join(".bashrc")));
+    assert!(is_symlink.eval(&to_path.join(".config/fish/config.fish")));
+    assert!(is_symlink.eval(&to_path.join("not-dot-file")));
+    assert!(predicate::path::exists()
+        .not()
+        .eval(&to_path.join("dot-bashrc")));
+
+    let mut cmd = Command::cargo_bin("kdot")?;
+    cmd.current_dir(tmp_dir.path())
+        .arg("status")
+        .arg("--format")
+        .arg("json");
+
+    let output = cmd.assert().success().get_output().stdout.clone();
+    let events = json_events(&output)?;
+    assert!(events
+        .iter()
+        .filter(|event| event["event"] == "status")
+        .all(|event| event["state"] == "linked"));
+
+    let mut cmd = Command::cargo_bin("kdot")?;
+    cmd.current_dir(tmp_dir.path()).arg("unlink").arg("bash");
+    cmd.assert().success();
+
+    assert!(predicate::path::exists()
+        .not()
+        .eval(&to_path.join(".bashrc")));
+
+    // Adopted "." files are renamed to "dot-" files
+    let config_path = tmp_dir.path().join("config");
+    fs::create_dir_all(config_path.join("git/.hooks"))?;
+    File::create(config_path.join("git/.hooks/pre-commit"))?;
+    File::create(config_path.join("git/.gitconfig"))?;
+
+    let mut cmd = Command::cargo_bin("kdot")?;
+    cmd.current_dir(tmp_dir.path())
+        .arg("init")
+        .arg("dots")
+        .arg("--scan")
+        .arg("config")
+        .arg("--adopt")
+        .arg("--dotfiles");
+    cmd.assert().success();
+
+    let dots_path = tmp_dir.path().join("dots");
+    assert!(dots_path.join("git/dot-hooks/pre-commit").is_file());
+    assert!(dots_path.join("git/dot-gitconfig").is_file());
+    assert!(is_symlink.eval(&config_path.join("git/.hooks/pre-commit")));
+    assert!(is_symlink.eval(&config_path.join("git/.gitconfig")));
+
+    // Renaming would overwrite a "dot-" file that is already there
+    let config_path = tmp_dir.path().join("config-conflict");
+    fs::create_dir_all(config_path.join("zsh"))?;
+    File::create(config_path.join("zsh/.zshrc"))?.write_all(b"dot")?;
+    File::create(config_path.join("zsh/dot-zshrc"))?.write_all(b"dash")?;
+
+    let mut cmd = Command::cargo_bin("kdot")?;
+    cmd.current_dir(tmp_dir.path())
+        .arg("init")
+        .arg("dots-conflict")
+        .arg("--scan")
+        .arg("config-conflict")
+        .arg("--adopt")
+        .arg("--dotfiles");
+    cmd.assert().code(4);
+
+    // Nothing was moved
+    assert_eq!(fs::read_to_string(config_path.join("zsh/.zshrc"))?, "dot");
+    assert_eq!(
+        fs::read_to_string(config_path.join("zsh/dot-zshrc"))?,
+        "dash"
+    );
+    assert!(predicate::path::exists()
+        .not()
+        .eval(&tmp_dir.path().join("dots-conflict/zsh")));
+
+    Ok(())
+}
+
+#[test]
+fn overrides_file_destinations() -> Result<(), Box<dyn std::error::Error>> {
+    let (tmp_dir, (from_path, from_path_string), (to_path, to_path_string)) = setup_config()?;
+
+    File::create(from_path.join(".bashrc"))?;
+    fs::create_dir_all(from_path.join("apps"))?;
+    File::create(from_path.join("apps/editor.desktop"))?;
+    File::create(from_path.join("notes.txt"))?;
+
+    let applications_path = tmp_dir.path().join("applications");
+    let notes_path = tmp_dir.path().join("other/notes-renamed.txt");
+
+    fs::write(
+        tmp_dir.path().join("kdot.json"),
+        json!({
+          "modules": [
+            {
+              "name": "bash",
+              "location": { "from": from_path_string, "to": to_path_string },
+              "files": {
+                "**/*.desktop": format!("{}/", applications_path.display()),
+                "notes.txt": notes_path
+              }
+            }
+          ]
+        })
+        .to_string(),
+    )?;
+
+    let mut cmd = Command::cargo_bin("kdot")?;
+    cmd.current_dir(tmp_dir.path()).arg("link").arg("bash");
+    cmd.assert().success();
+
+    let is_symlink = predicate::path::is_symlink();
+    let does_not_exist = predicate::path::exists().not();
+    assert!(is_symlink.eval(&to_path.join(".bashrc")));
+    assert!(is_symlink.eval(&applications_path.join("editor.desktop")));
+    assert!(is_symlink.eval(&notes_path));
+    assert!(does_not_exist.eval(&to_path.join("apps/editor.desktop")));
+    assert!(does_not_exist.eval(&to_path.join("notes.txt")));
+
+    let mut cmd = Command::cargo_bin("kdot")?;
+    cmd.current_dir(tmp_dir.path())
+        .arg("status")
+        .arg("--format")
+        .arg("json");
+
+    let output = cmd.assert().success().get_output().stdout.clone();
+    let events = json_events(&output)?;
+    let statuses: Vec<_> = events
+        .iter()
+        .filter(|event| event["event"] == "status")
+        .collect();
+    assert_eq!(statuses.len(), 3);
+    assert!(statuses.iter().all(|event| event["state"] == "linked"));
+    assert!(statuses
+        .iter()
+        .any(|event| event["path"] == notes_path.to_str().unwrap()));
+
+    let mut cmd = Command::cargo_bin("kdot")?;
+    cmd.current_dir(tmp_dir.path()).arg("unlink").arg("bash");
+    cmd.assert().success();
+
+    assert!(does_not_exist.eval(&to_path.join(".bashrc")));
+    assert!(does_not_exist.eval(&applications_path.join("editor.desktop")));
+    assert!(does_not_exist.eval(&notes_path));
+
+    // The files linked outside of a link folder that is gone are still unlinked
+    let mut cmd = Command::cargo_bin("kdot")?;
+    cmd.current_dir(tmp_dir.path()).arg("link").arg("bash");
+    cmd.assert().success();
+    fs::remove_dir_all(&to_path)?;
+
+    let mut cmd = Command::cargo_bin("kdot")?;
+    cmd.current_dir(tmp_dir.path()).arg("unlink").arg("bash");
+    cmd.assert().success();
+
+    assert!(does_not_exist.eval(&applications_path.join("editor.desktop")));
+    assert!(does_not_exist.eval(&notes_path));
+
+    Ok(())
+}
+
+#[test]
+fn links_multiple_locations() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp_dir = TempDir::new()?;
+
+    let dots_path = tmp_dir.path().join("nvim");
+    fs::create_dir_all(dots_path.join("config/lua"))?;
+    File::create(dots_path.join("config/init.lua"))?;
+    File::create(dots_path.join("config/lua/plugins.lua"))?;
+    fs::create_dir_all(dots_path.join("share/site"))?;
+    File::create(dots_path.join("share/site/spell.add"))?;
+    File::create(dots_path.join("share/colors.vim"))?;
+
+    let config_path = tmp_dir.path().join("home/.config/nvim");
+    let share_path = tmp_dir.path().join("home/.local/share/nvim");
+    fs::create_dir_all(&config_path)?;
+    fs::create_dir_all(&share_path)?;
+
+    fs::write(
+        tmp_dir.path().join("kdot.json"),
+        json!({
+          "modules": [
+            {
+              "name": "nvim",
+              "location": [
+                { "from": "nvim/config", "to": config_path },
+                { "from": "nvim/share", "to": share_path }
+              ]
+            }
+          ]
+        })
+        .to_string(),
+    )?;
+
+    let list = || -> Result<serde_json::Value, Box<dyn std::error::Error>> {
+        let mut cmd = Command::cargo_bin("kdot")?;
+        cmd.current_dir(tmp_dir.path())
+            .arg("list")
+            .arg("--format")
+            .arg("json");
+
+        let output = cmd.assert().success().get_output().stdout.clone();
+        Ok(serde_json::from_slice(&output)?)
+    };
+
+    assert_eq!(list()?[0]["locations"].as_array().unwrap().len(), 2);
+    assert_eq!(list()?[0]["status"], "unlinked");
+
+    let mut cmd = Command::cargo_bin("kdot")?;
+    cmd.current_dir(tmp_dir.path()).arg("link").arg("nvim");
+    cmd.assert().success();
+
+    let is_symlink = predicate::path::is_symlink();
+    assert!(is_symlink.eval(&config_path.join("init.lua")));
+    assert!(is_symlink.eval(&config_path.join("lua/plugins.lua")));
+    assert!(is_symlink.eval(&share_path.join("site/spell.add")));
+    assert_eq!(list()?[0]["status"], "linked");
+
+    let mut cmd = Command::cargo_bin("kdot")?;
+    cmd.current_dir(tmp_dir.path())
+        .arg("status")
+        .arg("--format")
+        .arg("json");
+
+    let output = cmd.assert().success().get_output().stdout.clone();
+    let events = json_events(&output)?;
+    assert_eq!(events.last().unwrap()["status"], 4);
+
+    let mut cmd = Command::cargo_bin("kdot")?;
+    cmd.current_dir(tmp_dir.path()).arg("unlink").arg("nvim");
+    cmd.assert().success();
+
+    assert_eq!(list()?[0]["status"], "unlinked");
+
+    // Unlinking removed the folders kdot created
+    assert!(predicate::path::exists()
+        .not()
+        .eval(&share_path.join("site")));
+
+    // A conflict in one location leaves the others unlinked too
+    fs::create_dir(share_path.join("site"))?;
+    File::create(share_path.join("site/spell.add"))?;
+
+    let mut cmd = Command::cargo_bin("kdot")?;
+    cmd.current_dir(tmp_dir.path()).arg("link").arg("nvim");
+    cmd.assert().failure();
+
+    assert!(predicate::path::exists()
+        .not()
+        .eval(&config_path.join("init.lua")));
+    // Including the links made in the failing location before the conflict
+    assert!(predicate::path::exists()
+        .not()
+        .eval(&share_path.join("colors.vim")));
+
+    Ok(())
+}
+
+#[test]
+fn escalates_root_modules() -> Result<(), Box<dyn std::error::Error>> {
+    let (tmp_dir, (from_path, from_path_string), (to_path, to_path_string)) = setup_config()?;
+
+    File::create(from_path.join("pacman.conf"))?;
+    fs::create_dir_all(from_path.join("udev/rules.d"))?;
+    File::create(from_path.join("udev/rules.d/99-keyboard.rules"))?;
+
+    let user_path = tmp_dir.path().join("user");
+    fs::create_dir_all(&user_path)?;
+    File::create(user_path.join("bashrc"))?;
+    let home_path = tmp_dir.path().join("home");
+    fs::create_dir_all(&home_path)?;
+
+    // Stands in for sudo, logging the privileged commands
+    let escalation = tmp_dir.path().join("fake-sudo");
+    let log = tmp_dir.path().join("escalation.log");
+    write_stub(
+        &escalation,
+        &format!("echo \"$1\" >> {}\nexec \"$@\"", log.display()),
+    )?;
+
+    fs::write(
+        tmp_dir.path().join("kdot.json"),
+        json!({
+          "escalation": escalation,
+          "modules": [
+            {
+              "name": "etc",
+              "location": { "from": from_path_string, "to": to_path_string },
+              "root": true,
+              "permissions": { "udev": "750" }
+            },
+            {
+              "name": "bash",
+              "location": { "from": user_path, "to": home_path }
+            }
+          ]
+        })
+        .to_string(),
+    )?;
+
+    let mut cmd = Command::cargo_bin("kdot")?;
+    cmd.current_dir(tmp_dir.path())
+        .arg("link")
+        .arg("etc")
+        .arg("bash");
+    cmd.assert().success();
+
+    let is_symlink = predicate::path::is_symlink();
+    assert!(is_symlink.eval(&to_path.join("pacman.conf")));
+    assert!(is_symlink.eval(&to_path.join("udev/rules.d/99-keyboard.rules")));
+    assert!(is_symlink.eval(&home_path.join("bashrc")));
+    assert_eq!(
+        fs::metadata(to_path.join("udev"))?.permissions().mode() & 0o777,
+        0o750
+    );
+
+    // Only the changes of the root module are escalated
+    let mut commands: Vec<String> = fs::read_to_string(&log)?
+        .lines()
+        .map(String::from)
+        .collect();
+    commands.sort();
+    assert_eq!(commands, ["chmod", "ln", "ln", "mkdir", "mkdir"]);
+
+    fs::remove_file(&log)?;
+
+    let mut cmd = Command::cargo_bin("kdot")?;
+    cmd.current_dir(tmp_dir.path()).arg("unlink").arg("etc");
+    cmd.assert().success();
+
+    assert!(predicate::path::exists()
+        .not()
+        .eval(&to_path.join("pacman.conf")));
+    assert_eq!(fs::read_to_string(&log)?, "rm\nrm\nrmdir\nrmdir\n");
+
+    // A failing escalation fails the link
+    write_stub(&escalation, "exit 1")?;
+
+    let mut cmd = Command::cargo_bin("kdot")?;
+    cmd.current_dir(tmp_dir.path()).arg("link").arg("etc");
+    cmd.assert().failure();
+
+    assert!(predicate::path::exists()
+        .not()
+        .eval(&to_path.join("pacman.conf")));
+
+    Ok(())
+}
+
+#[test]
+fn links_inside_root_folder() -> Result<(), Box<dyn std::error::Error>> {
+    let (tmp_dir, (from_path, from_path_string), _) = setup_config()?;
+
+    File::create(from_path.join("bashrc"))?;
+    fs::create_dir_all(from_path.join("apps"))?;
+    File::create(from_path.join("apps/editor.desktop"))?;
+
+    fs::write(
+        tmp_dir.path().join("kdot.json"),
+        json!({
+          "modules": [
+            {
+              "name": "bash",
+              "location": { "from": from_path_string, "to": "/home/kdot-test-user" },
+              "files": { "apps/*": "/usr/share/applications/" }
+            }
+          ]
+        })
+        .to_string(),
+    )?;
+
+    let stage_path = tmp_dir.path().join("stage");
+
+    let mut cmd = Command::cargo_bin("kdot")?;
+    cmd.current_dir(tmp_dir.path())
+        .arg("link")
+        .arg("bash")
+        .arg("--root")
+        .arg("stage");
+    cmd.assert().success();
+
+    let is_symlink = predicate::path::is_symlink();
+    assert!(is_symlink.eval(&stage_path.join("home/kdot-test-user/bashrc")));
+    assert!(is_symlink.eval(&stage_path.join("usr/share/applications/editor.desktop")));
+    assert!(predicate::path::exists()
+        .not()
+        .eval(Path::new("/home/kdot-test-user")));
+
+    let mut cmd = Command::cargo_bin("kdot")?;
+    cmd.current_dir(tmp_dir.path())
+        .arg("status")
+        .arg("--root")
+        .arg("stage")
+        .arg("--format")
+        .arg("json");
+
+    let output = cmd.assert().success().get_output().stdout.clone();
+    let events = json_events(&output)?;
+    assert_eq!(events[0]["state"], "linked");
+    assert_eq!(events[1]["state"], "linked");
+
+    let mut cmd = Command::cargo_bin("kdot")?;
+    cmd.current_dir(tmp_dir.path())
+        .arg("unlink")
+        .arg("bash")
+        .arg("--root")
+        .arg("stage");
+    cmd.assert().success();
+
+    assert!(predicate::path::exists()
+        .not()
+        .eval(&stage_path.join("home/kdot-test-user/bashrc")));
+
+    Ok(())
+}
+
+#[test]
+fn skips_host_changes_inside_root_folder() -> Result<(), Box<dyn std::error::Error>> {
+    let (tmp_dir, (from_path, from_path_string), _) = setup_config()?;
+
+    File::create(from_path.join("pacman.conf"))?;
+
+    let log = tmp_dir.path().join("host.log");
+    let escalation = tmp_dir.path().join("escalate");
+    let systemctl = tmp_dir.path().join("systemctl");
+    write_stub(
+        &escalation,
+        &format!("echo escalate >> {}\n\"$@\"", log.display()),
+    )?;
+    write_stub(&systemctl, &format!("echo systemctl >> {}", log.display()))?;
+
+    fs::write(
+        tmp_dir.path().join("kdot.json"),
+        json!({
+          "escalation": escalation,
+          "systemctl": systemctl,
+          "modules": [
+            {
+              "name": "etc",
+              "location": { "from": from_path_string, "to": "/etc/kdot-test" },
+              "root": true,
+              "services": ["kdot-test.service"],
+              "hooks": {
+                "pre_link": format!("echo hook >> {}", log.display()),
+                "post_unlink": format!("echo hook >> {}", log.display())
+              }
+            }
+          ]
+        })
+        .to_string(),
+    )?;
+
+    let mut cmd = Command::cargo_bin("kdot")?;
+    cmd.current_dir(tmp_dir.path())
+        .arg("link")
+        .arg("etc")
+        .arg("--root")
+        .arg("stage");
+    cmd.assert().success();
+
+    let stage_path = tmp_dir.path().join("stage");
+    assert!(predicate::path::is_symlink().eval(&stage_path.join("etc/kdot-test/pacman.conf")));
+
+    let mut cmd = Command::cargo_bin("kdot")?;
+    cmd.current_dir(tmp_dir.path())
+        .arg("unlink")
+        .arg("etc")
+        .arg("--root")
+        .arg("stage");
+    cmd.assert().success();
+
+    // No hook, service or escalation ran on the real system
+    assert!(!log.exists());
+
+    Ok(())
+}
+
+#[test]
+fn exits_with_error_codes() -> Result<(), Box<dyn std::error::Error>> {
+    let (tmp_dir, (from_path, _from_path_string), (to_path, _to_path_string)) = setup_config()?;
+
+    File::create(from_path.join("bashrc"))?;
+
+    // Unknown module
+    let mut cmd = Command::cargo_bin("kdot")?;
+    cmd.current_dir(tmp_dir.path()).arg("link").arg("zsh");
+    cmd.assert()
+        .code(3)
+        .stderr(predicate::str::contains("Invalid module \"zsh\"."));
+
+    // Also for the commands that default to every module
+    for command in &["status", "install"] {
+        let mut cmd = Command::cargo_bin("kdot")?;
+        cmd.current_dir(tmp_dir.path()).arg(command).arg("zsh");
+        cmd.assert().code(3);
+    }
+
+    // Something is in the way
+    File::create(to_path.join("bashrc"))?;
+
+    let mut cmd = Command::cargo_bin("kdot")?;
+    cmd.current_dir(tmp_dir.path()).arg("link").arg("bash");
+    cmd.assert().code(4);
+
+    // Not allowed to change the link folder
+    fs::remove_file(to_path.join("bashrc"))?;
+    fs::set_permissions(&to_path, fs::Permissions::from_mode(0o555))?;
+
+    let mut cmd = Command::cargo_bin("kdot")?;
+    cmd.current_dir(tmp_dir.path()).arg("link").arg("bash");
+    let assert = cmd.assert();
+    fs::set_permissions(&to_path, fs::Permissions::from_mode(0o755))?;
+    // Root ignores permissions
+    if !predicate::path::exists().eval(&to_path.join("bashrc")) {
+        assert.code(5);
+    }
+
+    // A file kdot did not link is left, once the rest is unlinked
+    let _ = fs::remove_file(to_path.join("bashrc"));
+    File::create(to_path.join("bashrc"))?;
+    File::create(from_path.join("inputrc"))?;
+    std::os::unix::fs::symlink(from_path.join("inputrc"), to_path.join("inputrc"))?;
+
+    let mut cmd = Command::cargo_bin("kdot")?;
+    cmd.current_dir(tmp_dir.path()).arg("unlink").arg("bash");
+    cmd.assert().code(6);
+    assert!(to_path.join("bashrc").is_file());
+    assert!(predicate::path::exists()
+        .not()
+        .eval(&to_path.join("inputrc")));
+
+    // Invalid kdot.json
+    fs::write(tmp_dir.path().join("kdot.json"), "{")?;
+
+    let mut cmd = Command::cargo_bin("kdot")?;
+    cmd.current_dir(tmp_dir.path()).arg("list");
+    cmd.assert().code(2);
+
+    Ok(())
+}
+
+#[test]
+fn handles_odd_file_names() -> Result<(), Box<dyn std::error::Error>> {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    let (tmp_dir, (from_path, _), (to_path, _)) = setup_config()?;
+
+    let not_utf8 = OsStr::from_bytes(b"caf\xe9");
+    let folder_not_utf8 = OsStr::from_bytes(b"d\xff");
+    let names = [
+        Path::new(not_utf8).to_path_buf(),
+        Path::new("with space").to_path_buf(),
+        Path::new("new\nline").to_path_buf(),
+        Path::new("-rf").to_path_buf(),
+        Path::new("--help/-x").to_path_buf(),
+        Path::new(folder_not_utf8).join("f"),
+    ];
+    fs::create_dir(from_path.join("--help"))?;
+    fs::create_dir(from_path.join(folder_not_utf8))?;
+    for name in &names {
+        File::create(from_path.join(name))?;
+    }
+
+    let mut cmd = Command::cargo_bin("kdot")?;
+    cmd.current_dir(tmp_dir.path())
+        .arg("link")
+        .arg("bash")
+        .arg("--format")
+        .arg("json");
+    cmd.assert().success();
+
+    let is_symlink = predicate::path::is_symlink();
+    for name in &names {
+        assert!(is_symlink.eval(&to_path.join(name)));
+    }
+
+    // Names that are not UTF-8 are reported lossily
+    let mut cmd = Command::cargo_bin("kdot")?;
+    cmd.current_dir(tmp_dir.path())
+        .arg("status")
+        .arg("--format")
+        .arg("json");
+
+    let output = cmd.assert().success().get_output().stdout.clone();
+    let events = json_events(&output)?;
+    let statuses: Vec<_> = events
+        .iter()
+        .filter(|event| event["event"] == "status")
+        .collect();
+    assert_eq!(statuses.len(), names.len());
+    assert!(statuses.iter().all(|event| event["state"] == "linked"));
+    assert!(statuses
+        .iter()
+        .any(|event| event["path"].as_str().unwrap().ends_with("caf\u{fffd}")));
+
+    let mut cmd = Command::cargo_bin("kdot")?;
+    cmd.current_dir(tmp_dir.path()).arg("status");
+    cmd.assert().success();
+
+    let mut cmd = Command::cargo_bin("kdot")?;
+    cmd.current_dir(tmp_dir.path()).arg("unlink").arg("bash");
+    cmd.assert().success();
+
+    for name in &names {
+        assert!(predicate::path::exists().not().eval(&to_path.join(name)));
+    }
+
+    // Folders kdot created are removed even if their names are not UTF-8
+    assert!(predicate::path::exists()
+        .not()
+        .eval(&to_path.join(folder_not_utf8)));
+
+    // Folders that are not UTF-8 cannot be modules
+    let config_path = tmp_dir.path().join("config");
+    fs::create_dir_all(config_path.join(not_utf8))?;
+    fs::create_dir_all(config_path.join("fish"))?;
+
+    let mut cmd = Command::cargo_bin("kdot")?;
+    cmd.current_dir(tmp_dir.path())
+        .arg("init")
+        .arg("dots")
+        .arg("--scan")
+        .arg("config");
+    cmd.assert().success();
+
+    let config = fs::read_to_string(tmp_dir.path().join("dots/kdot.json"))?;
+    assert!(config.contains("\"fish\""));
+
+    Ok(())
+}
+
+#[test]
+fn preserves_module_symlinks() -> Result<(), Box<dyn std::error::Error>> {
+    let (tmp_dir, (from_path, from_path_string), (to_path, to_path_string)) = setup_config()?;
+
+    File::create(from_path.join("bashrc"))?;
+    std::os::unix::fs::symlink("bashrc", from_path.join("zshrc"))?;
+    let shared_path = tmp_dir.path().join("shared");
+    fs::create_dir(&shared_path)?;
+    File::create(shared_path.join("init.vim"))?;
+    std::os::unix::fs::symlink(&shared_path, from_path.join("nvim"))?;
+
+    fs::write(
+        tmp_dir.path().join("kdot.json"),
+        json!({
+          "modules": [
+            {
+              "name": "bash",
+              "location": { "from": from_path_string, "to": to_path_string },
+              "symlinks": "preserve"
+            }
+          ]
+        })
+        .to_string(),
+    )?;
+
+    let mut cmd = Command::cargo_bin("kdot")?;
+    cmd.current_dir(tmp_dir.path()).arg("link").arg("bash");
+    cmd.assert().success();
+
+    assert_eq!(
+        fs::read_link(to_path.join("zshrc"))?,
+        from_path.join("zshrc")
+    );
+    assert_eq!(fs::read_link(to_path.join("nvim"))?, from_path.join("nvim"));
+    assert!(to_path.join("nvim/init.vim").is_file());
+
+    let mut cmd = Command::cargo_bin("kdot")?;
+    cmd.current_dir(tmp_dir.path()).arg("unlink").arg("bash");
+    cmd.assert().success();
+
+    assert!(fs::symlink_metadata(to_path.join("nvim")).is_err());
+    assert!(shared_path.join("init.vim").is_file());
+
+    // Rejected symlinks fail the link
+    fs::write(
+        tmp_dir.path().join("kdot.json"),
+        json!({
+          "modules": [
+            {
+              "name": "bash",
+              "location": { "from": from_path_string, "to": to_path_string },
+              "symlinks": "reject"
+            }
+          ]
+        })
+        .to_string(),
+    )?;
+
+    let mut cmd = Command::cargo_bin("kdot")?;
+    cmd.current_dir(tmp_dir.path()).arg("link").arg("bash");
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains("is a symlink"));
+
+    Ok(())
+}
+
+#[test]
+fn creates_empty_module_folders() -> Result<(), Box<dyn std::error::Error>> {
+    let (tmp_dir, (from_path, _), (to_path, _)) = setup_config()?;
+
+    fs::create_dir_all(from_path.join(".local/share/mpd/playlists"))?;
+    fs::create_dir_all(from_path.join("existing"))?;
+    fs::create_dir(to_path.join("existing"))?;
+
+    let mut cmd = Command::cargo_bin("kdot")?;
+    cmd.current_dir(tmp_dir.path()).arg("link").arg("bash");
+    cmd.assert().success();
+
+    let playlists_path = to_path.join(".local/share/mpd/playlists");
+    assert!(playlists_path.is_dir());
+
+    // Remembered as created by kdot
+    let state = fs::read_to_string(tmp_dir.path().join(".kdot-state.json"))?;
+    assert!(state.contains("playlists"));
+    assert!(!state.contains("existing"));
+
+    let mut cmd = Command::cargo_bin("kdot")?;
+    cmd.current_dir(tmp_dir.path())
+        .arg("status")
+        .arg("--format")
+        .arg("json");
+
+    let output = cmd.assert().success().get_output().stdout.clone();
+    let events = json_events(&output)?;
+    assert!(events
+        .iter()
+        .filter(|event| event["event"] == "status")
+        .all(|event| event["state"] == "linked"));
+
+    let mut cmd = Command::cargo_bin("kdot")?;
+    cmd.current_dir(tmp_dir.path()).arg("unlink").arg("bash");
+    cmd.assert().success();
+
+    assert!(!playlists_path.exists());
+    assert!(to_path.join("existing").is_dir());
+
+    Ok(())
+}
+
+#[test]
+fn removes_created_folders_on_unlink() -> Result<(), Box<dyn std::error::Error>> {
+    let (tmp_dir, (from_path, _), (to_path, _)) = setup_config()?;
+
+    fs::create_dir_all(from_path.join(".config/foo/bar"))?;
+    File::create(from_path.join(".config/foo/bar/baz.conf"))?;
+    fs::create_dir(to_path.join(".config"))?;
+
+    let mut cmd = Command::cargo_bin("kdot")?;
+    cmd.current_dir(tmp_dir.path()).arg("link").arg("bash");
+    cmd.assert().success();
+
+    assert!(predicate::path::is_symlink().eval(&to_path.join(".config/foo/bar/baz.conf")));
+
+    let mut cmd = Command::cargo_bin("kdot")?;
+    cmd.current_dir(tmp_dir.path())
+        .arg("unlink")
+        .arg("bash")
+        .arg("--format")
+        .arg("json");
+
+    let output = cmd.assert().success().get_output().stdout.clone();
+    let removed: Vec<_> = json_events(&output)?
+        .into_iter()
+        .filter(|event| event["event"] == "remove")
+        .collect();
+    assert_eq!(removed.len(), 3);
+
+    assert!(!to_path.join(".config/foo").exists());
+    assert!(to_path.join(".config").is_dir());
+
+    Ok(())
+}
@@ -0,0 +1,182 @@
+use serde_json::json;
+use std::fs::{self, File};
+use tempfile::TempDir;
+
+#[test]
+fn plans_applies_and_removes_modules() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp_dir = TempDir::new()?;
+
+    let from_path = tmp_dir.path().join("from");
+    fs::create_dir_all(from_path.join("fish"))?;
+    File::create(from_path.join("bashrc"))?;
+    File::create(from_path.join("fish/config.fish"))?;
+
+    let to_path = tmp_dir.path().join("to");
+    fs::create_dir(&to_path)?;
+
+    let config_path = tmp_dir.path().join("kdot.json");
+    fs::write(
+        &config_path,
+        json!({
+          "modules": [
+            {
+              "name": "bash",
+              "location": { "from": "from", "to": to_path }
+            }
+          ]
+        })
+        .to_string(),
+    )?;
+
+    // Relative to the folder of kdot.json, not the current directory
+    let config = kdot::load_config(&config_path)?;
+
+    assert!(kdot::resolve_modules(&config, &["zsh".to_owned()]).is_err());
+    let modules = kdot::resolve_modules(&config, &[])?;
+    assert_eq!(modules.len(), 1);
+    let module = modules[0];
+
+    assert_eq!(
+        kdot::plan(&config, module)?,
+        [
+            kdot::Change::Link {
+                path: to_path.join("bashrc"),
+                target: from_path.join("bashrc"),
+            },
+            kdot::Change::Mkdir {
+                path: to_path.join("fish"),
+            },
+            kdot::Change::Link {
+                path: to_path.join("fish/config.fish"),
+                target: from_path.join("fish/config.fish"),
+            },
+        ]
+    );
+
+    kdot::apply(&config, module)?;
+    assert!(tmp_dir.path().join(".kdot-state.json").is_file());
+
+    assert!(kdot::plan(&config, module)?.is_empty());
+    let status = kdot::status(&config, module)?;
+    assert_eq!(status.files.len(), 2);
+    assert!(status
+        .files
+        .iter()
+        .all(|file| file.state == kdot::FileState::Linked));
+
+    kdot::remove(&config, module)?;
+
+    // The folder kdot created is removed along with the links
+    assert!(!to_path.join("fish").exists());
+
+    assert!(kdot::status(&config, module)?
+        .files
+        .iter()
+        .all(|file| file.state == kdot::FileState::Unlinked));
+
+    Ok(())
+}
+
+#[test]
+fn serializes_plans_of_odd_file_names() -> Result<(), Box<dyn std::error::Error>> {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    let tmp_dir = TempDir::new()?;
+
+    let from_path = tmp_dir.path().join("from");
+    fs::create_dir_all(&from_path)?;
+    File::create(from_path.join(OsStr::from_bytes(b"caf\xe9")))?;
+
+    let to_path = tmp_dir.path().join("to");
+    fs::create_dir(&to_path)?;
+
+    let config_path = tmp_dir.path().join("kdot.json");
+    fs::write(
+        &config_path,
+        json!({
+          "modules": [
+            {
+              "name": "bash",
+              "location": { "from": from_path, "to": to_path }
+            }
+          ]
+        })
+        .to_string(),
+    )?;
+
+    let config = kdot::load_config(&config_path)?;
+    let module = kdot::resolve_modules(&config, &[])?[0];
+
+    // Names that are not UTF-8 are serialized lossily
+    let plan = serde_json::to_value(kdot::plan(&config, module)?)?;
+    assert_eq!(plan[0]["change"], "link");
+    assert!(plan[0]["path"].as_str().unwrap().ends_with("caf\u{fffd}"));
+    assert!(plan[0]["target"].as_str().unwrap().ends_with("caf\u{fffd}"));
+
+    Ok(())
+}
+
+#[test]
+fn links_modules_on_other_filesystems() -> Result<(), Box<dyn std::error::Error>> {
+    use kdot::filesystem::{memory::MemoryFilesystem, FileType, Filesystem};
+    use kdot::module::{self, Runtime};
+    use std::path::Path;
+
+    let fs = MemoryFilesystem::new();
+    fs.add_file("/dots/bash/bashrc", b"bash")?;
+    fs.add_file("/dots/bash/fish/config.fish", b"fish")?;
+    fs.add_file("/dots/nvim/init.lua", b"nvim")?;
+    fs.add_file("/home/user/.config/nvim/init.lua", b"in the way")?;
+
+    let mut config: kdot::PackageConfig = serde_json::from_value(json!({
+      "modules": [
+        {
+          "name": "bash",
+          "location": [
+            { "from": "bash", "to": "/home/user" },
+            { "from": "nvim", "to": "/home/user/.config/nvim" }
+          ]
+        }
+      ]
+    }))?;
+    config.base_dir = "/dots".into();
+    let module = &config.modules[0];
+    let runtime = Runtime {
+        root: None,
+        fs: Some(&fs),
+    };
+
+    // The second location conflicts, so the first one is rolled back
+    assert!(module::link_module(&config, &runtime, module).is_err());
+    assert!(fs.file_type(Path::new("/home/user/bashrc")).is_err());
+    assert!(fs.file_type(Path::new("/home/user/fish")).is_err());
+
+    fs.remove_file(Path::new("/home/user/.config/nvim/init.lua"))?;
+    module::link_module(&config, &runtime, module)?;
+
+    assert_eq!(
+        fs.read_link(Path::new("/home/user/fish/config.fish"))?,
+        Path::new("/dots/bash/fish/config.fish")
+    );
+    assert_eq!(
+        fs.read(Path::new("/home/user/.config/nvim/init.lua"))?,
+        b"nvim"
+    );
+    assert!(module::get_module_status(&config, &runtime, module)?
+        .files
+        .iter()
+        .all(|file| file.state == kdot::FileState::Linked));
+
+    // The state is kept on the same filesystem, next to kdot.json
+    assert!(fs.is_file(Path::new("/dots/.kdot-state.json")));
+
+    module::unlink_module(&config, &runtime, module)?;
+    assert!(fs.file_type(Path::new("/home/user/fish")).is_err());
+    assert_eq!(
+        fs.file_type(Path::new("/home/user/.config/nvim"))?,
+        FileType::Dir
+    );
+
+    Ok(())
+}